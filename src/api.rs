@@ -1,65 +1,442 @@
-use crate::db::{Database, DatabaseError};
-use crate::Synced;
-use routes::{ApiDBError, IDParsingError};
-use warp::http::Method;
+use crate::auth::{Auth, AuthError};
+use crate::db::{Database, DatabaseError, MarkerPolicy};
+use crate::events::EventHub;
+use crate::storage::{Storage, StorageError};
+use routes::{
+    ApiAuthError, ApiDBError, ApiStorageError, CursorParsingError, FormParsingError,
+    IDParsingError, ValidationError, NEXT_CURSOR_HEADER,
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use warp::http::{Method, StatusCode};
+use warp::path::FullPath;
+use warp::reject;
 use warp::Filter;
 
+mod openapi;
 mod routes;
+mod validation;
+
+#[cfg(test)]
+mod test;
+
+// 今のAPIはここに置く
+// 前はルートに置いていたので、そちらも Deprecation ヘッダを付けてしばらく残す
+const API_PREFIX: &str = "/api/v1";
 
 const CONTENT_LENGTH_LIMIT: u64 = 1024 * 16;
+const UPLOAD_SIZE_LIMIT: u64 = 1024 * 1024 * 16;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    port: u16,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) {
+    let route =
+        filter(db, storage, auth, Arc::clone(&events), marker_policy).with(warp::log("api"));
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+    let (_, server) = warp::serve(route).bind_with_graceful_shutdown(([0, 0, 0, 0], port), async {
+        stop_rx.await.ok();
+    });
+
+    let server = tokio::spawn(server);
+
+    shutdown.await;
+    log::info!("waiting for in-flight requests to finish");
+
+    // 繋ぎっぱなしのWebSocketやSSEがあると終われないので、先に閉じる
+    events.close();
+    let _ = stop_tx.send(());
+
+    if timeout(drain_timeout, server).await.is_err() {
+        log::warn!(
+            "some requests did not finish within {} seconds, dropping them",
+            drain_timeout.as_secs()
+        );
+    }
+}
 
-pub async fn serve(port: u16, db: Synced<impl Database>) {
+// 全ルートに CORS とエラーの変換を被せたもの
+// 断られたときも必ずJSONのエラーを返すので、ここから先で Rejection は出ない
+fn filter(
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
+) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
     let cors = warp::cors::cors()
         .allow_any_origin()
-        .allow_headers(vec!["content-type"])
+        .allow_headers(vec!["content-type", "authorization"])
+        .expose_headers(vec![NEXT_CURSOR_HEADER, "deprecation", "link"])
         .allow_methods(&[
             Method::GET,
             Method::PUT,
+            Method::PATCH,
             Method::DELETE,
             Method::POST,
             Method::OPTIONS,
         ]);
 
-    let route = routes::routes(db)
+    let api = routes::routes(db, storage, auth, events, marker_policy).or(openapi::openapi());
+
+    let v1 = warp::path!("api" / "v1" / ..).and(api.clone());
+
+    // クエリが無いと warp::query::raw() は断るので、そのときは空にする
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+
+    let legacy =
+        warp::path::full()
+            .and(query)
+            .and(api)
+            .map(|path: FullPath, query: String, reply| {
+                let mut successor = format!("{}{}", API_PREFIX, path.as_str());
+                if !query.is_empty() {
+                    successor.push('?');
+                    successor.push_str(&query);
+                }
+
+                let link = format!("<{}>; rel=\"successor-version\"", successor);
+                let reply = warp::reply::with_header(reply, "deprecation", "true");
+                warp::reply::with_header(reply, "link", link)
+            });
+
+    // CORSで断られたときもJSONで返すように、外側でもう一度拾う
+    v1.or(legacy)
+        .recover(recover_error)
+        .with(cors)
         .recover(recover_error)
-        .with(warp::log("api"))
-        .with(cors);
+}
 
-    warp::serve(route).run(([0, 0, 0, 0], port)).await;
+// エラーは全部この形のJSONで返す
+// フロントエンドは code で分岐する (message は人が読むためのもの)
+#[derive(Serialize, JsonSchema)]
+struct ErrorBody {
+    code: &'static str,
+    message: &'static str,
+    details: Option<serde_json::Value>,
 }
 
-// warp::reject::custom()したやつはここで拾わないとwarpがエラー吐く
-// それ以外(warpが用意してるやつ)はここで拾わず受け流せばwarpがいい感じにしてくれる
-async fn recover_error(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+fn error_reply(
+    status: StatusCode,
+    code: &'static str,
+    message: &'static str,
+    details: Option<serde_json::Value>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let body = ErrorBody {
+        code,
+        message,
+        details,
+    };
+
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+// warp::reject::custom()したやつも、warpが用意してるやつも、全部ここでJSONにする
+async fn recover_error(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
     if let Some(db_err) = err.find::<ApiDBError>() {
-        return match db_err.0 {
-            DatabaseError::ClassNotFound => Ok(warp::reply::with_status(
+        return Ok(match db_err.0 {
+            DatabaseError::ClassNotFound => error_reply(
+                StatusCode::NOT_FOUND,
+                "class_not_found",
                 "Not found such class id",
-                warp::http::StatusCode::NOT_FOUND,
-            )),
+                None,
+            ),
 
-            DatabaseError::FileNotFound => Ok(warp::reply::with_status(
+            DatabaseError::FileNotFound => error_reply(
+                StatusCode::NOT_FOUND,
+                "file_not_found",
                 "Not found such file id",
-                warp::http::StatusCode::NOT_FOUND,
-            )),
+                None,
+            ),
+
+            DatabaseError::MarkerConflict => error_reply(
+                StatusCode::CONFLICT,
+                "marker_conflict",
+                "Specified marker is already used in this class",
+                None,
+            ),
+
+            DatabaseError::Conflict => error_reply(
+                StatusCode::CONFLICT,
+                "conflict",
+                "Generated id collided with another request, please retry",
+                None,
+            ),
+
+            DatabaseError::TeacherNotFound => error_reply(
+                StatusCode::NOT_FOUND,
+                "teacher_not_found",
+                "Not found such teacher",
+                None,
+            ),
 
             _ => {
                 log::error!("Database error occur: {:?}", db_err);
 
-                Ok(warp::reply::with_status(
+                error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "database_error",
                     "Internal Server Error (Cannot retrieve data from database)",
-                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-                ))
+                    None,
+                )
             }
-        };
+        });
+    }
+
+    if let Some(storage_err) = err.find::<ApiStorageError>() {
+        return Ok(match storage_err.0 {
+            StorageError::NotFound => error_reply(
+                StatusCode::NOT_FOUND,
+                "content_not_found",
+                "Not found content of such file id",
+                None,
+            ),
+
+            StorageError::AlreadyExists => error_reply(
+                StatusCode::CONFLICT,
+                "conflict",
+                "Generated id collided with another request, please retry",
+                None,
+            ),
+
+            _ => {
+                log::error!("Storage error occur: {:?}", storage_err);
+
+                error_reply(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "storage_error",
+                    "Internal Server Error (Cannot access file storage)",
+                    None,
+                )
+            }
+        });
+    }
+
+    if let Some(auth_err) = err.find::<ApiAuthError>() {
+        return Ok(match auth_err.0 {
+            AuthError::MissingToken => error_reply(
+                StatusCode::UNAUTHORIZED,
+                "missing_token",
+                "Authorization required",
+                None,
+            ),
+
+            AuthError::InvalidToken => error_reply(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "Invalid or expired token",
+                None,
+            ),
+
+            AuthError::ExpiredToken => error_reply(
+                StatusCode::UNAUTHORIZED,
+                "expired_token",
+                "Invalid or expired token",
+                None,
+            ),
+
+            AuthError::InvalidCredentials => error_reply(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Wrong name or password",
+                None,
+            ),
+
+            AuthError::NameAlreadyTaken => error_reply(
+                StatusCode::CONFLICT,
+                "name_already_taken",
+                "Such teacher name is already taken",
+                None,
+            ),
+
+            AuthError::NotOwner => error_reply(
+                StatusCode::FORBIDDEN,
+                "not_owner",
+                "You are not the owner of this class",
+                None,
+            ),
+
+            AuthError::ReadOnlyToken => error_reply(
+                StatusCode::FORBIDDEN,
+                "read_only_token",
+                "Student token is read-only",
+                None,
+            ),
+
+            AuthError::OutOfScope => error_reply(
+                StatusCode::FORBIDDEN,
+                "out_of_scope",
+                "Token is not issued for this class",
+                None,
+            ),
+        });
     }
 
-    if err.find::<IDParsingError>().is_some() {
-        return Ok(warp::reply::with_status(
+    if let Some(id_err) = err.find::<IDParsingError>() {
+        log::debug!("Invalid id: {}", id_err.0);
+
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_id",
             "Invalid id format",
-            warp::http::StatusCode::BAD_REQUEST,
+            Some(id_err.0.to_string().into()),
+        ));
+    }
+
+    if let Some(cursor_err) = err.find::<CursorParsingError>() {
+        log::debug!("Invalid cursor: {}", cursor_err.0);
+
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_cursor",
+            "Invalid cursor",
+            None,
+        ));
+    }
+
+    if let Some(validation_err) = err.find::<ValidationError>() {
+        log::debug!("Invalid request body: {:?}", validation_err.0);
+
+        return Ok(error_reply(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "validation_failed",
+            "Some fields of request body are invalid",
+            serde_json::to_value(&validation_err.0).ok(),
         ));
     }
 
-    Err(err)
+    if let Some(form_err) = err.find::<FormParsingError>() {
+        log::debug!("Invalid upload form: {}", form_err.0);
+
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_form",
+            "Invalid upload form",
+            Some(form_err.0.to_string().into()),
+        ));
+    }
+
+    Ok(recover_warp_error(&err))
+}
+
+// warpが用意してるエラー
+// 複数のルートで断られたときに warp が選ぶのと同じになるように、404と405は最後に見る
+fn recover_warp_error(err: &warp::Rejection) -> warp::reply::WithStatus<warp::reply::Json> {
+    if let Some(body_err) = err.find::<warp::body::BodyDeserializeError>() {
+        return error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_body",
+            "Invalid request body",
+            Some(body_err.to_string().into()),
+        );
+    }
+
+    if err.find::<reject::PayloadTooLarge>().is_some() {
+        return error_reply(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Request body is too large",
+            None,
+        );
+    }
+
+    if err.find::<reject::LengthRequired>().is_some() {
+        return error_reply(
+            StatusCode::LENGTH_REQUIRED,
+            "length_required",
+            "Content-Length header is required",
+            None,
+        );
+    }
+
+    if err.find::<reject::UnsupportedMediaType>().is_some() {
+        return error_reply(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Unsupported content type",
+            None,
+        );
+    }
+
+    if err.find::<reject::InvalidQuery>().is_some() {
+        return error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            "Invalid query string",
+            None,
+        );
+    }
+
+    if let Some(header_err) = err.find::<reject::MissingHeader>() {
+        return error_reply(
+            StatusCode::BAD_REQUEST,
+            "missing_header",
+            "Missing request header",
+            Some(header_err.name().into()),
+        );
+    }
+
+    if let Some(header_err) = err.find::<reject::InvalidHeader>() {
+        return error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_header",
+            "Invalid request header",
+            Some(header_err.name().into()),
+        );
+    }
+
+    if err.find::<warp::ws::MissingConnectionUpgrade>().is_some() {
+        return error_reply(
+            StatusCode::BAD_REQUEST,
+            "missing_upgrade",
+            "WebSocket upgrade required",
+            None,
+        );
+    }
+
+    if let Some(cors_err) = err.find::<warp::cors::CorsForbidden>() {
+        return error_reply(
+            StatusCode::FORBIDDEN,
+            "cors_forbidden",
+            "CORS request forbidden",
+            Some(cors_err.to_string().into()),
+        );
+    }
+
+    if err.find::<reject::MethodNotAllowed>().is_some() {
+        return error_reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "HTTP method not allowed",
+            None,
+        );
+    }
+
+    if err.is_not_found() {
+        return error_reply(StatusCode::NOT_FOUND, "not_found", "Not found", None);
+    }
+
+    log::error!("unhandled rejection: {:?}", err);
+
+    error_reply(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "Internal Server Error",
+        None,
+    )
 }
@@ -0,0 +1,259 @@
+use super::routes;
+use super::{ErrorBody, API_PREFIX};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use warp::Filter;
+
+const SWAGGER_UI_HTML: &str = include_str!("swagger-ui.html");
+
+// Swagger UI 本体 (swagger-ui-dist 5.17.14) はバイナリに同梱して自分で配る
+// 外のCDNから読み込むと、そこで書き換えられたスクリプトがトークンを読めてしまう
+const SWAGGER_UI_CSS: &str = include_str!("swagger-ui/swagger-ui.css");
+const SWAGGER_UI_JS: &str = include_str!("swagger-ui/swagger-ui-bundle.js");
+
+// 仕様書は起動時に一度だけ作る
+pub(super) fn openapi() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    let document = Arc::new(document());
+
+    let json = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(document.as_ref()));
+
+    let docs = warp::path!("docs")
+        .and(warp::get())
+        .map(|| warp::reply::html(SWAGGER_UI_HTML));
+
+    let css = warp::path!("docs" / "swagger-ui.css")
+        .and(warp::get())
+        .map(|| asset(SWAGGER_UI_CSS, "text/css; charset=utf-8"));
+
+    let js = warp::path!("docs" / "swagger-ui-bundle.js")
+        .and(warp::get())
+        .map(|| asset(SWAGGER_UI_JS, "application/javascript; charset=utf-8"));
+
+    json.or(docs).or(css).or(js)
+}
+
+fn asset(body: &'static str, content_type: &'static str) -> impl warp::Reply {
+    warp::reply::with_header(body, "content-type", content_type)
+}
+
+// 各ルートのモジュールが返す Operation と、Rustの型から作ったスキーマをまとめる
+fn document() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let operations = routes::describe(&mut gen);
+    let error = to_value(gen.subschema_for::<ErrorBody>());
+
+    let mut paths = Map::new();
+
+    for operation in operations {
+        let item = paths
+            .entry(operation.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap();
+
+        item.insert(operation.method.into(), operation.into_value(&error));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Blackboard API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": API_PREFIX }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                // WebSocketやEventSourceはヘッダを付けられないので、クエリでも受け付けている
+                "tokenQuery": { "type": "apiKey", "in": "query", "name": "token" },
+            },
+        },
+    })
+}
+
+fn to_value(schema: Schema) -> Value {
+    serde_json::to_value(schema).unwrap()
+}
+
+pub(super) struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    authorized: bool,
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    responses: Map<String, Value>,
+}
+
+impl Operation {
+    pub(super) fn new(method: &'static str, path: &'static str, summary: &'static str) -> Self {
+        Operation {
+            method,
+            path,
+            summary,
+            authorized: false,
+            parameters: vec![],
+            request_body: None,
+            responses: Map::new(),
+        }
+    }
+
+    // トークンが要る (先生だけか生徒も使えるかは summary に書く)
+    pub(super) fn authorized(mut self) -> Self {
+        self.authorized = true;
+        self
+    }
+
+    pub(super) fn path_param<T: JsonSchema>(
+        mut self,
+        name: &'static str,
+        gen: &mut SchemaGenerator,
+    ) -> Self {
+        self.parameters.push(json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "schema": to_value(gen.subschema_for::<T>()),
+        }));
+        self
+    }
+
+    // クエリを受け取る構造体の項目を、そのままクエリパラメータにする
+    pub(super) fn query<T: JsonSchema>(mut self, gen: &mut SchemaGenerator) -> Self {
+        let object = T::json_schema(gen).into_object().object.unwrap_or_default();
+
+        for (name, schema) in object.properties {
+            // 省略できることは required で表すので、Option から付く nullable は外す
+            let mut schema = to_value(schema);
+            schema.as_object_mut().map(|s| s.remove("nullable"));
+
+            self.parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": object.required.contains(&name),
+                "schema": schema,
+            }));
+        }
+
+        self
+    }
+
+    pub(super) fn json_body<T: JsonSchema>(self, gen: &mut SchemaGenerator) -> Self {
+        let schema = to_value(gen.subschema_for::<T>());
+        self.body("application/json", json!({ "schema": schema }))
+    }
+
+    pub(super) fn body(mut self, content_type: &str, media: Value) -> Self {
+        self.request_body = Some(json!({
+            "required": true,
+            "content": { content_type: media },
+        }));
+        self
+    }
+
+    pub(super) fn json_response<T: JsonSchema>(
+        self,
+        status: u16,
+        description: &str,
+        gen: &mut SchemaGenerator,
+    ) -> Self {
+        let schema = to_value(gen.subschema_for::<T>());
+        self.content_response(status, description, "application/json", schema)
+    }
+
+    pub(super) fn content_response(
+        mut self,
+        status: u16,
+        description: &str,
+        content_type: &str,
+        schema: Value,
+    ) -> Self {
+        self.responses.insert(
+            status.to_string(),
+            json!({
+                "description": description,
+                "content": { content_type: { "schema": schema } },
+            }),
+        );
+        self
+    }
+
+    pub(super) fn empty_response(mut self, status: u16, description: &str) -> Self {
+        self.responses
+            .insert(status.to_string(), json!({ "description": description }));
+        self
+    }
+
+    // エラーは全部 recover_error で同じ形にしているので、default にまとめる
+    fn into_value(mut self, error: &Value) -> Value {
+        self.responses.insert(
+            "default".into(),
+            json!({
+                "description": "Error",
+                "content": { "application/json": { "schema": error } },
+            }),
+        );
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": self.parameters,
+            "responses": self.responses,
+        });
+
+        if let Some(body) = self.request_body {
+            operation["requestBody"] = body;
+        }
+
+        if self.authorized {
+            operation["security"] = json!([{ "bearer": [] }, { "tokenQuery": [] }]);
+        }
+
+        operation
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collect_refs(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(reference)) = map.get("$ref") {
+                    refs.push(reference.clone());
+                }
+
+                map.values().for_each(|v| collect_refs(v, refs));
+            }
+
+            Value::Array(values) => values.iter().for_each(|v| collect_refs(v, refs)),
+
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_ref_is_defined() {
+        let document = document();
+        let schemas = &document["components"]["schemas"];
+
+        let mut refs = vec![];
+        collect_refs(&document, &mut refs);
+        assert!(!refs.is_empty());
+
+        for reference in refs {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(schemas.get(name).is_some(), "{} is not defined", reference);
+        }
+
+        assert!(document["paths"]["/classes/{classID}/files/{fileID}"]["patch"].is_object());
+    }
+}
@@ -1,15 +1,29 @@
 mod by_pass;
 mod class;
 mod classes;
+mod events;
+mod login;
+mod marker;
 mod resource;
 mod resources;
+mod teachers;
 
+use super::openapi::Operation;
+use super::validation::{validate, FieldErrors, Validate};
 use super::CONTENT_LENGTH_LIMIT;
-use crate::db::{Database, DatabaseError};
-use crate::Synced;
+use crate::auth::{Auth, AuthError, Principal};
+use crate::db::{Database, DatabaseError, MarkerPolicy};
+use crate::events::EventHub;
+use crate::model::{Class, ClassID, File, TeacherID};
+use crate::storage::{Storage, StorageError};
+use schemars::gen::SchemaGenerator;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use std::sync::Arc;
-use warp::Filter;
+use thiserror::Error;
+use warp::http::HeaderValue;
+use warp::{Filter, Reply};
 
 macro_rules! warp_err {
     ( $(struct $struct_name:ident($from:ty);)* ) => {
@@ -23,32 +37,299 @@ macro_rules! warp_err {
 
 warp_err! {
     struct ApiDBError(DatabaseError);
+    struct ApiStorageError(StorageError);
+    struct ApiAuthError(AuthError);
     struct IDParsingError(uuid::Error);
+    struct FormParsingError(FormError);
+    struct CursorParsingError(CursorError);
+    struct ValidationError(FieldErrors);
 }
 
+#[derive(Error, Debug)]
+pub(super) enum CursorError {
+    #[error("cursor is not base64: {0}")]
+    InvalidEncoding(base64::DecodeError),
+
+    #[error("cursor is not valid json: {0}")]
+    InvalidContent(serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub(super) enum FormError {
+    #[error("missing \"{0}\" part")]
+    MissingPart(&'static str),
+
+    #[error("invalid metadata: {0}")]
+    InvalidMetadata(serde_json::Error),
+
+    #[error("failed to read form: {0}")]
+    ReadFailed(warp::Error),
+}
+
+// 一覧で一度に返す件数
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 200;
+
+// 次のページのカーソルはヘッダで返す (本文は今まで通りの配列にしておく)
+pub(super) const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
 // returns filter that combined all filters in child modules.
 pub(super) fn routes(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    classes::classes(&db)
-        .or(class::class(&db))
-        .or(resources::resources(&db))
-        .or(resource::resource(&db))
-        .or(by_pass::by_pass(&db))
+    classes::classes(&db, &auth)
+        .or(class::class(&db, &storage, &auth, &events))
+        .or(resources::resources(
+            &db,
+            &storage,
+            &auth,
+            &events,
+            marker_policy,
+        ))
+        .or(resource::resource(
+            &db,
+            &storage,
+            &auth,
+            &events,
+            marker_policy,
+        ))
+        .or(events::events(&db, &auth, &events))
+        .or(marker::marker(&db, &auth))
+        .or(by_pass::by_pass(&db, &auth))
+        .or(teachers::teachers(&db))
+        .or(login::login(&db, &auth))
+}
+
+// /openapi.json に載せる、全ルートの説明
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![
+        classes::describe(gen),
+        class::describe(gen),
+        resources::describe(gen),
+        resource::describe(gen),
+        events::describe(gen),
+        marker::describe(gen),
+        by_pass::describe(gen),
+        teachers::describe(gen),
+        login::describe(gen),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
 }
 
 fn with_json_body<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
 where
-    T: DeserializeOwned + Send,
+    T: DeserializeOwned + Validate + Send,
 {
-    warp::body::content_length_limit(CONTENT_LENGTH_LIMIT).and(warp::body::json())
+    warp::body::content_length_limit(CONTENT_LENGTH_LIMIT)
+        .and(warp::body::json())
+        .and_then(|body: T| async move {
+            validate(body)
+                .map_err(ValidationError)
+                .map_err(warp::reject::custom)
+        })
 }
 
 fn with_db<D>(
-    db: Synced<D>,
-) -> impl Filter<Extract = (Synced<D>,), Error = std::convert::Infallible> + Clone
+    db: Arc<D>,
+) -> impl Filter<Extract = (Arc<D>,), Error = std::convert::Infallible> + Clone
 where
     D: Database,
 {
     warp::any().map(move || Arc::clone(&db))
 }
+
+fn with_storage<S>(
+    storage: Arc<S>,
+) -> impl Filter<Extract = (Arc<S>,), Error = std::convert::Infallible> + Clone
+where
+    S: Storage,
+{
+    warp::any().map(move || Arc::clone(&storage))
+}
+
+fn with_events(
+    events: Arc<EventHub>,
+) -> impl Filter<Extract = (Arc<EventHub>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&events))
+}
+
+fn with_auth(
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = (Arc<Auth>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&auth))
+}
+
+// WebSocketや<img>のようにヘッダを付けられないクライアントのために、
+// "?token=<token>" でもトークンを受け付ける
+#[derive(Deserialize, Default)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// "Authorization: Bearer <token>" を検証して、リクエストしてきた人を取り出す
+fn with_principal(
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = (Principal,), Error = warp::Rejection> + Clone {
+    let query = warp::query::<TokenQuery>()
+        .or(warp::any().map(TokenQuery::default))
+        .unify();
+
+    warp::header::optional::<String>("authorization")
+        .and(query)
+        .and(with_auth(auth))
+        .and_then(authenticate)
+}
+
+// 先生のトークンだけ通す
+fn with_teacher(
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = (TeacherID,), Error = warp::Rejection> + Clone {
+    with_principal(auth).and_then(require_teacher)
+}
+
+async fn authenticate(
+    header: Option<String>,
+    query: TokenQuery,
+    auth: Arc<Auth>,
+) -> Result<Principal, warp::Rejection> {
+    let token = match (&header, &query.token) {
+        (Some(header), _) => header
+            .strip_prefix("Bearer ")
+            .ok_or(AuthError::InvalidToken)
+            .map_err(ApiAuthError)
+            .map_err(warp::reject::custom)?,
+
+        (None, Some(token)) => token.as_str(),
+
+        (None, None) => return Err(warp::reject::custom(ApiAuthError(AuthError::MissingToken))),
+    };
+
+    auth.verify_token(token)
+        .map_err(ApiAuthError)
+        .map_err(warp::reject::custom)
+}
+
+async fn require_teacher(principal: Principal) -> Result<TeacherID, warp::Rejection> {
+    match principal {
+        Principal::Teacher { id } => Ok(id),
+        Principal::Student { .. } => {
+            Err(warp::reject::custom(ApiAuthError(AuthError::ReadOnlyToken)))
+        }
+    }
+}
+
+// {id} のクラスの所有者からのリクエストでなければ弾く
+// 変更系のルートは、パスとメソッドが一致してからハンドラの中でこれを通す
+// 一致する前に調べると、他のルートに行くリクエストでもDBを引いてしまい、404 や 405 が 401 や 403 になる
+async fn owned_class(
+    db: &impl Database,
+    raw_id: &str,
+    teacher_id: &TeacherID,
+) -> Result<Class, warp::Rejection> {
+    let class = db
+        .get_class_by_id(&parse_class_id(raw_id)?)
+        .await
+        .map_err(ApiDBError)
+        .map_err(warp::reject::custom)?;
+
+    if class.owner != *teacher_id {
+        return Err(warp::reject::custom(ApiAuthError(AuthError::NotOwner)));
+    }
+
+    Ok(class)
+}
+
+// owned_class の閲覧版
+// 所有者の先生に加えて、そのクラスの合言葉から発行された生徒のトークンも通す
+async fn readable_class(
+    db: &impl Database,
+    raw_id: &str,
+    principal: &Principal,
+) -> Result<ClassID, warp::Rejection> {
+    match principal {
+        Principal::Teacher { id } => owned_class(db, raw_id, id).await.map(|class| class.id),
+
+        Principal::Student { class } => {
+            let id = parse_class_id(raw_id)?;
+
+            if *class != id {
+                return Err(warp::reject::custom(ApiAuthError(AuthError::OutOfScope)));
+            }
+
+            Ok(id)
+        }
+    }
+}
+
+fn parse_class_id(raw_id: &str) -> Result<ClassID, warp::Rejection> {
+    ClassID::from_str(raw_id)
+        .map_err(IDParsingError)
+        .map_err(warp::reject::custom)
+}
+
+// DBから消したファイルの中身をストレージからも消す
+// メタデータは既に消えているので、失敗してもログだけ残して続行する
+async fn delete_contents(storage: &impl Storage, files: &[File]) {
+    for file in files {
+        match storage.delete(&file.id).await {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => log::error!("failed to delete content of file {:?}: {:?}", file.id, e),
+        }
+    }
+}
+
+fn page_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+}
+
+// カーソルはクライアントから見て中身を気にしなくていいように、JSONをbase64にして渡す
+fn encode_cursor(cursor: &impl Serialize) -> String {
+    base64::encode_config(serde_json::to_vec(cursor).unwrap(), base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor<T: DeserializeOwned>(raw: &str) -> Result<T, warp::Rejection> {
+    let bytes = base64::decode_config(raw, base64::URL_SAFE_NO_PAD)
+        .map_err(CursorError::InvalidEncoding)
+        .map_err(CursorParsingError)
+        .map_err(warp::reject::custom)?;
+
+    serde_json::from_slice(&bytes)
+        .map_err(CursorError::InvalidContent)
+        .map_err(CursorParsingError)
+        .map_err(warp::reject::custom)
+}
+
+// items は limit より1件多く取ってきたもの
+// はみ出した分があれば、まだ続きがあるのでカーソルを付けて返す
+fn paged_reply<T, C>(
+    mut items: Vec<T>,
+    limit: usize,
+    cursor_of: impl Fn(&T) -> C,
+) -> warp::reply::Response
+where
+    T: Serialize,
+    C: Serialize,
+{
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|last| encode_cursor(&cursor_of(last)))
+    } else {
+        None
+    };
+
+    let mut response = warp::reply::json(&items).into_response();
+
+    if let Some(cursor) = next_cursor {
+        response
+            .headers_mut()
+            .insert(NEXT_CURSOR_HEADER, HeaderValue::from_str(&cursor).unwrap());
+    }
+
+    response
+}
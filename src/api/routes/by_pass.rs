@@ -1,39 +1,69 @@
-use crate::api::routes::with_db;
+use crate::api::openapi::Operation;
+use crate::api::routes::{with_auth, with_db};
 use crate::api::ApiDBError;
+use crate::auth::Auth;
 use crate::db::Database;
-use crate::model::PassPhrase;
-use crate::Synced;
+use crate::model::{ClassID, PassPhrase};
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::Serialize;
 use std::sync::Arc;
 use warp::Filter;
 
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![Operation::new(
+        "get",
+        "/class/by-pass/{passPhrase}",
+        "合言葉から生徒用の閲覧トークンをもらう",
+    )
+    .path_param::<PassPhrase>("passPhrase", gen)
+    .json_response::<GetResponseBody>(200, "クラスと閲覧トークン", gen)]
+}
+
 pub(super) fn by_pass(
-    db: &Synced<impl Database>,
+    db: &Arc<impl Database>,
+    auth: &Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get(Arc::clone(db))
+    get(Arc::clone(db), Arc::clone(auth))
 }
 
 fn get(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("class" / "by-pass" / String)
         .and(warp::get())
         .and(with_db(db))
+        .and(with_auth(auth))
         .and_then(on_get)
 }
 
+// 生徒には合言葉からクラスの閲覧専用トークンだけを渡す
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "StudentTokenResponse")]
+struct GetResponseBody {
+    #[serde(rename = "classID")]
+    class_id: ClassID,
+    name: String,
+    token: String,
+}
+
 async fn on_get(
     pass: String,
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let pass = PassPhrase(pass);
 
     let class = db
-        .lock()
-        .await
         .get_class_by_pass_phrase(&pass)
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
-    Ok(warp::reply::json(&class))
+    Ok(warp::reply::json(&GetResponseBody {
+        token: auth.issue_student_token(&class.id),
+        class_id: class.id,
+        name: class.name,
+    }))
 }
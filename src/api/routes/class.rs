@@ -1,106 +1,216 @@
-use super::{with_db, with_json_body, ApiDBError, IDParsingError};
+use super::{
+    delete_contents, owned_class, readable_class, with_db, with_events, with_json_body,
+    with_principal, with_storage, with_teacher, ApiDBError,
+};
+use crate::api::openapi::Operation;
+use crate::api::validation::{FieldErrors, Validate, MAX_NAME_LENGTH};
+use crate::auth::{Auth, Principal};
 use crate::db::Database;
-use crate::model::ClassID;
-use crate::Synced;
-use serde::Deserialize;
-use std::str::FromStr;
+use crate::events::{EventHub, EventKind};
+use crate::model::{Class, ClassID, EpochTime, File, TeacherID};
+use crate::storage::Storage;
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::sync::Arc;
 use warp::Filter;
 
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let class = json!({
+        "oneOf": [gen.subschema_for::<Class>(), gen.subschema_for::<StudentView>()],
+    });
+
+    vec![
+        Operation::new(
+            "get",
+            "/classes/{classID}",
+            "クラスとファイルの一覧 (所有者の先生か、そのクラスの生徒)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .content_response(
+            200,
+            "クラス (生徒のトークンでは合言葉と所有者を除く)",
+            "application/json",
+            class,
+        ),
+        Operation::new(
+            "put",
+            "/classes/{classID}",
+            "クラスの名前を変える (所有者の先生のみ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .json_body::<PutRequestBody>(gen)
+        .empty_response(204, "変更した"),
+        Operation::new(
+            "delete",
+            "/classes/{classID}",
+            "クラスを消す (所有者の先生のみ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .json_response::<Class>(200, "消したクラス", gen),
+    ]
+}
+
 pub(super) fn class(
-    db: &Synced<impl Database>,
+    db: &Arc<impl Database>,
+    storage: &Arc<impl Storage>,
+    auth: &Arc<Auth>,
+    events: &Arc<EventHub>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get(Arc::clone(db))
-        .or(put(Arc::clone(db)))
-        .or(delete(Arc::clone(db)))
+    get(Arc::clone(db), Arc::clone(auth))
+        .or(put(Arc::clone(db), Arc::clone(auth), Arc::clone(events)))
+        .or(delete(
+            Arc::clone(db),
+            Arc::clone(storage),
+            Arc::clone(auth),
+            Arc::clone(events),
+        ))
 }
 
 fn get(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String)
         .and(warp::get())
+        .and(with_principal(auth))
         .and(with_db(db))
         .and_then(on_get)
 }
 
+// 生徒には合言葉と所有者を見せない
+// 合言葉が分かると、他の人にも閲覧トークンを配れてしまう
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "StudentClass")]
+struct StudentView {
+    name: String,
+    id: ClassID,
+
+    #[serde(rename = "createdAt")]
+    created_at: EpochTime,
+
+    #[serde(rename = "updatedAt")]
+    updated_at: EpochTime,
+
+    files: Vec<File>,
+}
+
+impl From<Class> for StudentView {
+    fn from(class: Class) -> Self {
+        StudentView {
+            name: class.name,
+            id: class.id,
+            created_at: class.created_at,
+            updated_at: class.updated_at,
+            files: class.files,
+        }
+    }
+}
+
 async fn on_get(
     raw_id: String,
-    db: Synced<impl Database>,
+    principal: Principal,
+    db: Arc<impl Database>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let id = ClassID::from_str(raw_id.as_str())
-        .map_err(IDParsingError)
-        .map_err(warp::reject::custom)?;
+    // 先生なら、所有者かを確かめるときに読んだクラスをそのまま返す
+    if let Principal::Teacher { id } = &principal {
+        let class = owned_class(db.as_ref(), &raw_id, id).await?;
+        return Ok(warp::reply::json(&class));
+    }
+
+    let id = readable_class(db.as_ref(), &raw_id, &principal).await?;
 
     let class = db
-        .lock()
-        .await
         .get_class_by_id(&id)
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
-    Ok(warp::reply::json(&class))
+    Ok(warp::reply::json(&StudentView::from(class)))
 }
 
 fn put(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String)
         .and(warp::put())
+        .and(with_teacher(auth))
         .and(with_db(db))
+        .and(with_events(events))
         .and(with_json_body())
         .and_then(on_put)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "RenameClassRequest")]
 struct PutRequestBody {
     name: String,
 }
 
+impl Validate for PutRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("name", &self.name);
+        errors.max_length("name", &self.name, MAX_NAME_LENGTH);
+    }
+}
+
 async fn on_put(
     raw_id: String,
-    db: Synced<impl Database>,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
+    events: Arc<EventHub>,
     body: PutRequestBody,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let id = ClassID::from_str(raw_id.as_str())
-        .map_err(IDParsingError)
-        .map_err(warp::reject::custom)?;
+    let id = owned_class(db.as_ref(), &raw_id, &teacher_id).await?.id;
 
-    db.lock()
-        .await
-        .rename_class(&id, body.name.as_str())
+    db.rename_class(&id, body.name.as_str())
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
+    events.publish(&id, EventKind::ClassRenamed { name: body.name });
+
     Ok(warp::http::StatusCode::NO_CONTENT)
 }
 
 fn delete(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String)
         .and(warp::delete())
+        .and(with_teacher(auth))
         .and(with_db(db))
+        .and(with_storage(storage))
+        .and(with_events(events))
         .and_then(on_delete)
 }
 
 async fn on_delete(
     raw_id: String,
-    db: Synced<impl Database>,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    events: Arc<EventHub>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let id = ClassID::from_str(raw_id.as_str())
-        .map_err(IDParsingError)
-        .map_err(warp::reject::custom)?;
+    let id = owned_class(db.as_ref(), &raw_id, &teacher_id).await?.id;
 
     let class = db
-        .lock()
-        .await
         .delete_class(&id)
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
+    delete_contents(storage.as_ref(), &class.files).await;
+    events.publish(&id, EventKind::ClassDeleted);
+
     Ok(warp::reply::json(&class))
 }
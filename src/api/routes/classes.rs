@@ -1,68 +1,139 @@
-use super::{with_db, with_json_body, ApiDBError};
-use crate::db::Database;
-use crate::model::Class;
-use crate::Synced;
+use super::{
+    decode_cursor, page_limit, paged_reply, with_db, with_json_body, with_teacher, ApiDBError,
+};
+use crate::api::openapi::Operation;
+use crate::api::validation::{FieldErrors, Validate, MAX_NAME_LENGTH};
+use crate::auth::Auth;
+use crate::db::{
+    ClassCursor, ClassQuery, ClassSortKey, Database, DatabaseError, SimpleClassInfo, SortOrder,
+    CONFLICT_RETRIES,
+};
+use crate::model::{Class, TeacherID};
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use std::sync::Arc;
 use warp::Filter;
 
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![
+        Operation::new(
+            "get",
+            "/classes",
+            "自分のクラスの一覧 (先生のみ、次のページのカーソルは x-next-cursor ヘッダ)",
+        )
+        .authorized()
+        .query::<GetQuery>(gen)
+        .json_response::<Vec<SimpleClassInfo>>(200, "クラスの一覧", gen),
+        Operation::new("post", "/classes", "クラスを作る (先生のみ)")
+            .authorized()
+            .json_body::<PostRequestBody>(gen)
+            .json_response::<Class>(200, "作ったクラス", gen),
+    ]
+}
+
 pub(super) fn classes(
-    db: &Synced<impl Database>,
+    db: &Arc<impl Database>,
+    auth: &Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get(Arc::clone(db)).or(post(Arc::clone(db)))
+    get(Arc::clone(db), Arc::clone(auth)).or(post(Arc::clone(db), Arc::clone(auth)))
 }
 
+// 合言葉が載っているので、先生には自分のクラスだけを返す
 fn get(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes")
         .and(warp::get())
+        .and(with_teacher(auth))
+        .and(warp::query::<GetQuery>())
         .and(with_db(db))
         .and_then(on_get)
 }
 
-async fn on_get(db: Synced<impl Database>) -> Result<impl warp::Reply, warp::Rejection> {
+#[derive(Deserialize, JsonSchema)]
+struct GetQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    sort: Option<ClassSortKey>,
+    order: Option<SortOrder>,
+    name: Option<String>,
+}
+
+async fn on_get(
+    teacher_id: TeacherID,
+    query: GetQuery,
+    db: Arc<impl Database>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let limit = page_limit(query.limit);
+
+    let after = match &query.cursor {
+        Some(cursor) => Some(decode_cursor::<ClassCursor>(cursor)?),
+        None => None,
+    };
+
     let classes = db
-        .lock()
-        .await
-        .get_all_classes()
+        .query_classes(&ClassQuery {
+            owner: Some(teacher_id),
+            name_contains: query.name,
+            sort: query.sort.unwrap_or(ClassSortKey::CreatedAt),
+            order: query.order.unwrap_or(SortOrder::Ascending),
+            after,
+            limit: limit + 1,
+        })
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
-    Ok(warp::reply::json(&classes))
+    Ok(paged_reply(classes, limit, |c| ClassCursor::from(c)))
 }
 
 fn post(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes")
         .and(warp::post())
+        .and(with_teacher(auth))
         .and(with_db(db))
         .and(with_json_body())
         .and_then(on_post)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "CreateClassRequest")]
 struct PostRequestBody {
     name: String,
 }
 
+impl Validate for PostRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("name", &self.name);
+        errors.max_length("name", &self.name, MAX_NAME_LENGTH);
+    }
+}
+
 async fn on_post(
-    db: Synced<impl Database>,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
     body: PostRequestBody,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let class = Class::new(&db, body.name)
-        .await
-        .map_err(ApiDBError)
-        .map_err(warp::reject::custom)?;
+    let mut retries = 0;
 
-    db.lock()
-        .await
-        .save_new_class(&class)
-        .await
-        .map_err(ApiDBError)
-        .map_err(warp::reject::custom)?;
+    // IDか合言葉が同時に作られたクラスと被ったら、作り直す
+    let class = loop {
+        let class = Class::new(db.as_ref(), body.name.clone(), teacher_id.clone())
+            .await
+            .map_err(ApiDBError)
+            .map_err(warp::reject::custom)?;
+
+        match db.save_new_class(&class).await {
+            Ok(()) => break class,
+            Err(DatabaseError::Conflict) if retries < CONFLICT_RETRIES => retries += 1,
+            Err(e) => return Err(warp::reject::custom(ApiDBError(e))),
+        }
+    };
 
     Ok(warp::reply::json(&class))
 }
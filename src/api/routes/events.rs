@@ -0,0 +1,199 @@
+use super::{readable_class, with_db, with_events, with_principal};
+use crate::api::openapi::Operation;
+use crate::auth::{Auth, Principal};
+use crate::db::Database;
+use crate::events::{ClassEvent, EventHub, EventKind};
+use crate::model::ClassID;
+use futures::{SinkExt, Stream, StreamExt};
+use schemars::gen::SchemaGenerator;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use warp::sse::ServerSentEvent;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Filter;
+
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let event = serde_json::to_value(gen.subschema_for::<ClassEvent>()).unwrap();
+
+    vec![
+        Operation::new(
+            "get",
+            "/classes/{classID}/events",
+            "クラスの変更を WebSocket で受け取る (メッセージは ClassEvent のJSON)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .empty_response(101, "WebSocket に切り替える"),
+        Operation::new(
+            "get",
+            "/classes/{classID}/events/sse",
+            "クラスの変更を Server-Sent Events で受け取る (イベントの data に入る)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .content_response(200, "Server-Sent Events", "text/event-stream", event),
+    ]
+}
+
+pub(super) fn events(
+    db: &Arc<impl Database>,
+    auth: &Arc<Auth>,
+    events: &Arc<EventHub>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    ws(Arc::clone(db), Arc::clone(auth), Arc::clone(events)).or(sse(
+        Arc::clone(db),
+        Arc::clone(auth),
+        Arc::clone(events),
+    ))
+}
+
+fn ws(
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("classes" / String / "events")
+        .and(warp::get())
+        .and(with_principal(auth))
+        .and(warp::ws())
+        .and(with_db(db))
+        .and(with_events(events))
+        .and_then(on_ws)
+}
+
+async fn on_ws(
+    raw_id: String,
+    principal: Principal,
+    ws: Ws,
+    db: Arc<impl Database>,
+    events: Arc<EventHub>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+
+    // 接続してからのイベントを取りこぼさないように、アップグレード前に購読しておく
+    let receiver = events.subscribe();
+    Ok(ws.on_upgrade(move |socket| send_events(socket, class_id, events, receiver)))
+}
+
+async fn send_events(
+    socket: WebSocket,
+    class_id: ClassID,
+    events: Arc<EventHub>,
+    mut receiver: broadcast::Receiver<ClassEvent>,
+) {
+    let (mut tx, mut rx) = socket.split();
+
+    loop {
+        tokio::select! {
+            // クライアントからのメッセージは読み捨てて、切断だけ検知する
+            message = rx.next() => match message {
+                Some(Ok(message)) if !message.is_close() => continue,
+                _ => break,
+            },
+
+            // 受信が追いつかなかったときは Resync が来るので、クライアントに全部取り直してもらう
+            event = events.next_event(&mut receiver, &class_id) => match event {
+                Some(event) => {
+                    let text = serde_json::to_string(&event).unwrap();
+
+                    if tx.send(Message::text(text)).await.is_err() {
+                        break;
+                    }
+
+                    if let EventKind::ClassDeleted = event.kind {
+                        break;
+                    }
+                }
+
+                None => break,
+            },
+        }
+    }
+
+    let _ = tx.send(Message::close()).await;
+}
+
+fn sse(
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("classes" / String / "events" / "sse")
+        .and(warp::get())
+        .and(with_principal(auth))
+        .and(warp::sse::last_event_id::<u64>())
+        .and(with_db(db))
+        .and(with_events(events))
+        .and_then(on_sse)
+}
+
+async fn on_sse(
+    raw_id: String,
+    principal: Principal,
+    last_event_id: Option<u64>,
+    db: Arc<impl Database>,
+    events: Arc<EventHub>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+
+    // Last-Event-ID が付いていたら、それ以降に取りこぼしたイベントから送る
+    let (missed, receiver) = match last_event_id {
+        Some(last_seq) => events.subscribe_from(&class_id, last_seq),
+        None => (Ok(vec![]), events.subscribe()),
+    };
+
+    let missed = missed.unwrap_or_else(|seq| {
+        vec![ClassEvent {
+            class_id: class_id.clone(),
+            seq,
+            kind: EventKind::Resync,
+        }]
+    });
+
+    let stream = futures::stream::iter(missed)
+        .chain(live_events(class_id, receiver))
+        .map(to_sse);
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+// 受信が追いつかなかったときはストリームを閉じる
+// クライアントが Last-Event-ID 付きで再接続してくれば、取りこぼした分はそこで送り直せる
+fn live_events(
+    class_id: ClassID,
+    receiver: broadcast::Receiver<ClassEvent>,
+) -> impl Stream<Item = ClassEvent> {
+    futures::stream::unfold(Some(receiver), move |receiver| {
+        let class_id = class_id.clone();
+
+        async move {
+            let mut receiver = receiver?;
+
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.class_id == class_id => {
+                        // クラスが消えたらそれ以上イベントは来ないので閉じる
+                        let next = match event.kind {
+                            EventKind::ClassDeleted => None,
+                            _ => Some(receiver),
+                        };
+
+                        return Some((event, next));
+                    }
+
+                    Ok(_) => continue,
+                    Err(_) => return None,
+                }
+            }
+        }
+    })
+}
+
+fn to_sse(event: ClassEvent) -> Result<impl ServerSentEvent, Infallible> {
+    Ok((
+        warp::sse::id(event.seq),
+        warp::sse::event(event.kind.name()),
+        warp::sse::json(event),
+    ))
+}
@@ -0,0 +1,95 @@
+use super::{with_auth, with_db, with_json_body, ApiAuthError, ApiDBError};
+use crate::api::openapi::Operation;
+use crate::api::validation::{FieldErrors, Validate, MAX_NAME_LENGTH, MAX_PASSWORD_LENGTH};
+use crate::auth::{dummy_password_hash, verify_password, Auth, AuthError};
+use crate::db::{Database, DatabaseError};
+use crate::model::TeacherID;
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::Filter;
+
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![Operation::new("post", "/login", "先生のトークンをもらう")
+        .json_body::<PostRequestBody>(gen)
+        .json_response::<PostResponseBody>(200, "先生のトークン", gen)]
+}
+
+pub(super) fn login(
+    db: &Arc<impl Database>,
+    auth: &Arc<Auth>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    post(Arc::clone(db), Arc::clone(auth))
+}
+
+fn post(
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("login")
+        .and(warp::post())
+        .and(with_db(db))
+        .and(with_auth(auth))
+        .and(with_json_body())
+        .and_then(on_post)
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "LoginRequest")]
+struct PostRequestBody {
+    name: String,
+    password: String,
+}
+
+impl Validate for PostRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("name", &self.name);
+        errors.max_length("name", &self.name, MAX_NAME_LENGTH);
+        errors.required("password", &self.password);
+        errors.max_length("password", &self.password, MAX_PASSWORD_LENGTH);
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "LoginResponse")]
+struct PostResponseBody {
+    #[serde(rename = "teacherID")]
+    teacher_id: TeacherID,
+    token: String,
+}
+
+async fn on_post(
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+    body: PostRequestBody,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let teacher = match db.get_teacher_by_name(&body.name).await {
+        Ok(teacher) => Some(teacher),
+        Err(DatabaseError::TeacherNotFound) => None,
+        Err(e) => return Err(warp::reject::custom(ApiDBError(e))),
+    };
+
+    // 名前が存在するかどうかを教えないように、パスワード違いと同じエラーにする
+    // 返すまでの時間でも分からないように、名前が無くてもダミーのハッシュで同じだけ照合する
+    let password_hash = match &teacher {
+        Some(teacher) => teacher.password_hash.clone(),
+        None => dummy_password_hash(),
+    };
+
+    let verified = verify_password(&body.password, &password_hash).await;
+
+    let teacher = match teacher {
+        Some(teacher) if verified => teacher,
+        _ => {
+            return Err(warp::reject::custom(ApiAuthError(
+                AuthError::InvalidCredentials,
+            )))
+        }
+    };
+
+    Ok(warp::reply::json(&PostResponseBody {
+        token: auth.issue_teacher_token(&teacher.id),
+        teacher_id: teacher.id,
+    }))
+}
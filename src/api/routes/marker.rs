@@ -0,0 +1,61 @@
+use super::{readable_class, with_db, with_principal, ApiDBError};
+use crate::api::openapi::Operation;
+use crate::auth::{Auth, Principal};
+use crate::db::Database;
+use crate::model::{ArMarkerID, ClassID, File};
+use percent_encoding::percent_decode_str;
+use schemars::gen::SchemaGenerator;
+use std::sync::Arc;
+use warp::Filter;
+
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![Operation::new(
+        "get",
+        "/classes/{classID}/markers/{markerID}",
+        "マーカーに紐付いたファイル",
+    )
+    .authorized()
+    .path_param::<ClassID>("classID", gen)
+    .path_param::<ArMarkerID>("markerID", gen)
+    .json_response::<Vec<File>>(200, "ファイルの一覧 (無ければ空)", gen)]
+}
+
+pub(super) fn marker(
+    db: &Arc<impl Database>,
+    auth: &Arc<Auth>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    get(Arc::clone(db), Arc::clone(auth))
+}
+
+fn get(
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("classes" / String / "markers" / String)
+        .and(warp::get())
+        .and(with_principal(auth))
+        .and(with_db(db))
+        .and_then(on_get)
+}
+
+// ARのクライアントが見つけたマーカーに紐付いたファイルを返す
+async fn on_get(
+    raw_id: String,
+    raw_marker_id: String,
+    principal: Principal,
+    db: Arc<impl Database>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+
+    // パスのパラメータはパーセントエンコードされたまま来る
+    let marker_id = percent_decode_str(&raw_marker_id).decode_utf8_lossy();
+    let marker_id = ArMarkerID(marker_id.into_owned());
+
+    let resources = db
+        .get_files_by_marker(&class_id, &marker_id)
+        .await
+        .map_err(ApiDBError)
+        .map_err(warp::reject::custom)?;
+
+    Ok(warp::reply::json(&resources))
+}
@@ -1,71 +1,305 @@
-use super::{with_db, ApiDBError, IDParsingError};
-use crate::db::Database;
-use crate::model::FileID;
-use crate::Synced;
+use super::{
+    delete_contents, owned_class, readable_class, with_db, with_events, with_json_body,
+    with_principal, with_storage, with_teacher, ApiDBError, ApiStorageError, IDParsingError,
+};
+use crate::api::openapi::Operation;
+use crate::api::validation::{FieldErrors, Validate, MAX_MARKER_ID_LENGTH};
+use crate::auth::{Auth, Principal};
+use crate::db::{Database, FileUpdate, MarkerPolicy};
+use crate::events::{EventHub, EventKind};
+use crate::model::{ArMarkerID, ClassID, File, FileID, TeacherID};
+use crate::storage::Storage;
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
 use std::str::FromStr;
 use std::sync::Arc;
 use warp::Filter;
 
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let binary = json!({ "type": "string", "format": "binary" });
+
+    vec![
+        Operation::new("get", "/classes/{classID}/files/{fileID}", "ファイルの情報")
+            .authorized()
+            .path_param::<ClassID>("classID", gen)
+            .path_param::<FileID>("fileID", gen)
+            .json_response::<File>(200, "ファイル", gen),
+        Operation::new(
+            "patch",
+            "/classes/{classID}/files/{fileID}",
+            "マーカーや名前を変える (所有者の先生のみ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .path_param::<FileID>("fileID", gen)
+        .json_body::<PatchRequestBody>(gen)
+        .json_response::<File>(200, "変更したファイル", gen),
+        Operation::new(
+            "delete",
+            "/classes/{classID}/files/{fileID}",
+            "ファイルを消す (所有者の先生のみ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .path_param::<FileID>("fileID", gen)
+        .json_response::<File>(200, "消したファイル", gen),
+        Operation::new(
+            "get",
+            "/classes/{classID}/files/{fileID}/content",
+            "ファイルの中身",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .path_param::<FileID>("fileID", gen)
+        .content_response(
+            200,
+            "ファイル名から推測した Content-Type で返す",
+            "application/octet-stream",
+            binary,
+        ),
+    ]
+}
+
 pub(super) fn resource(
-    db: &Synced<impl Database>,
+    db: &Arc<impl Database>,
+    storage: &Arc<impl Storage>,
+    auth: &Arc<Auth>,
+    events: &Arc<EventHub>,
+    marker_policy: MarkerPolicy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get(Arc::clone(db)).or(delete(Arc::clone(db)))
+    get(Arc::clone(db), Arc::clone(auth))
+        .or(get_content(
+            Arc::clone(db),
+            Arc::clone(storage),
+            Arc::clone(auth),
+        ))
+        .or(patch(
+            Arc::clone(db),
+            Arc::clone(storage),
+            Arc::clone(auth),
+            Arc::clone(events),
+            marker_policy,
+        ))
+        .or(delete(
+            Arc::clone(db),
+            Arc::clone(storage),
+            Arc::clone(auth),
+            Arc::clone(events),
+        ))
 }
 
 fn get(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String / "files" / String)
         .and(warp::get())
+        .and(with_principal(auth))
         .and(with_db(db))
         .and_then(on_get)
 }
 
 async fn on_get(
-    _: String,
+    raw_id: String,
     raw_resource_id: String,
-    db: Synced<impl Database>,
+    principal: Principal,
+    db: Arc<impl Database>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+
     let resource_id = FileID::from_str(raw_resource_id.as_str())
         .map_err(IDParsingError)
         .map_err(warp::reject::custom)?;
 
     let resource = db
-        .lock()
+        .get_file_in_class(&class_id, &resource_id)
         .await
-        .get_file_by_id(&resource_id)
+        .map_err(ApiDBError)
+        .map_err(warp::reject::custom)?;
+
+    Ok(warp::reply::json(&resource))
+}
+
+fn get_content(
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("classes" / String / "files" / String / "content")
+        .and(warp::get())
+        .and(with_principal(auth))
+        .and(with_db(db))
+        .and(with_storage(storage))
+        .and_then(on_get_content)
+}
+
+async fn on_get_content(
+    raw_id: String,
+    raw_resource_id: String,
+    principal: Principal,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+
+    let resource_id = FileID::from_str(raw_resource_id.as_str())
+        .map_err(IDParsingError)
+        .map_err(warp::reject::custom)?;
+
+    let resource = db
+        .get_file_in_class(&class_id, &resource_id)
+        .await
+        .map_err(ApiDBError)
+        .map_err(warp::reject::custom)?;
+
+    let content = storage
+        .load(&resource.id)
+        .await
+        .map_err(ApiStorageError)
+        .map_err(warp::reject::custom)?;
+
+    let content_type = mime_guess::from_path(&resource.resource_info.filename)
+        .first_or_octet_stream()
+        .to_string();
+
+    Ok(warp::reply::with_header(
+        content,
+        "content-type",
+        content_type,
+    ))
+}
+
+fn patch(
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("classes" / String / "files" / String)
+        .and(warp::patch())
+        .and(with_teacher(auth))
+        .and(with_db(db))
+        .and(with_storage(storage))
+        .and(with_events(events))
+        .and(warp::any().map(move || marker_policy))
+        .and(with_json_body())
+        .and_then(on_patch)
+}
+
+// 送られてきた項目だけを書き換える (IDは変わらない)
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "UpdateFileRequest")]
+struct PatchRequestBody {
+    #[serde(rename = "markerID")]
+    marker_id: Option<String>,
+
+    #[serde(rename = "fileName")]
+    file_name: Option<String>,
+}
+
+impl Validate for PatchRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if let Some(marker_id) = &self.marker_id {
+            errors.required("markerID", marker_id);
+            errors.max_length("markerID", marker_id, MAX_MARKER_ID_LENGTH);
+        }
+
+        if let Some(file_name) = &self.file_name {
+            errors.file_name("fileName", file_name);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn on_patch(
+    raw_id: String,
+    raw_resource_id: String,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
+    body: PatchRequestBody,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = owned_class(db.as_ref(), &raw_id, &teacher_id).await?.id;
+
+    let resource_id = FileID::from_str(raw_resource_id.as_str())
+        .map_err(IDParsingError)
+        .map_err(warp::reject::custom)?;
+
+    let update = FileUpdate {
+        marker_id: body.marker_id.map(ArMarkerID),
+        file_name: body.file_name,
+    };
+
+    let (resource, replaced) = db
+        .update_file(&class_id, &resource_id, &update, marker_policy)
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
+    // 付け替え先のマーカーから外されたファイルは、アップロードで置き換えたときと同じく消える
+    delete_contents(storage.as_ref(), &replaced).await;
+
+    for file in replaced {
+        events.publish(&class_id, EventKind::FileDeleted { file });
+    }
+
+    events.publish(
+        &class_id,
+        EventKind::FileUpdated {
+            file: resource.clone(),
+        },
+    );
+
     Ok(warp::reply::json(&resource))
 }
 
 fn delete(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String / "files" / String)
         .and(warp::delete())
+        .and(with_teacher(auth))
         .and(with_db(db))
+        .and(with_storage(storage))
+        .and(with_events(events))
         .and_then(on_delete)
 }
 
 async fn on_delete(
-    _: String,
+    raw_id: String,
     raw_resource_id: String,
-    db: Synced<impl Database>,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    events: Arc<EventHub>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
+    let class_id = owned_class(db.as_ref(), &raw_id, &teacher_id).await?.id;
+
     let resource_id = FileID::from_str(raw_resource_id.as_str())
         .map_err(IDParsingError)
         .map_err(warp::reject::custom)?;
 
     let resource = db
-        .lock()
-        .await
-        .delete_file(&resource_id)
+        .delete_file_in_class(&class_id, &resource_id)
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
+    delete_contents(storage.as_ref(), std::slice::from_ref(&resource)).await;
+    events.publish(
+        &class_id,
+        EventKind::FileDeleted {
+            file: resource.clone(),
+        },
+    );
+
     Ok(warp::reply::json(&resource))
 }
@@ -1,57 +1,163 @@
-use super::{with_db, with_json_body, ApiDBError, IDParsingError};
-use crate::db::Database;
-use crate::model::{ArMarkerID, ClassID, EpochTime, File};
-use crate::Synced;
+use super::{
+    decode_cursor, delete_contents, owned_class, page_limit, paged_reply, readable_class, with_db,
+    with_events, with_principal, with_storage, with_teacher, ApiDBError, ApiStorageError,
+    FormError, FormParsingError, ValidationError,
+};
+use crate::api::openapi::Operation;
+use crate::api::validation::{validate, FieldErrors, Validate, MAX_MARKER_ID_LENGTH};
+use crate::api::UPLOAD_SIZE_LIMIT;
+use crate::auth::{Auth, Principal};
+use crate::db::{
+    Database, DatabaseError, FileCursor, FileQuery, MarkerPolicy, SortOrder, CONFLICT_RETRIES,
+};
+use crate::events::{EventHub, EventKind};
+use crate::model::{ArMarkerID, ClassID, EpochTime, File, TeacherID};
+use crate::storage::{Storage, StorageError};
+use bytes::Buf;
+use futures::TryStreamExt;
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::str::FromStr;
+use serde_json::json;
 use std::sync::Arc;
+use warp::multipart::{FormData, Part};
 use warp::Filter;
 
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    let metadata = gen.subschema_for::<PostRequestBody>();
+
+    let upload = json!({
+        "schema": {
+            "type": "object",
+            "required": ["metadata", "file"],
+            "properties": {
+                "metadata": metadata,
+                "file": { "type": "string", "format": "binary" },
+            },
+        },
+        "encoding": { "metadata": { "contentType": "application/json" } },
+    });
+
+    vec![
+        Operation::new(
+            "get",
+            "/classes/{classID}/files",
+            "ファイルの一覧 (次のページのカーソルは x-next-cursor ヘッダ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .query::<GetQuery>(gen)
+        .json_response::<Vec<File>>(200, "ファイルの一覧", gen),
+        Operation::new(
+            "post",
+            "/classes/{classID}/files",
+            "ファイルを上げる (所有者の先生のみ)",
+        )
+        .authorized()
+        .path_param::<ClassID>("classID", gen)
+        .body("multipart/form-data", upload)
+        .json_response::<File>(200, "追加したファイル", gen),
+    ]
+}
+
 pub(super) fn resources(
-    db: &Synced<impl Database>,
+    db: &Arc<impl Database>,
+    storage: &Arc<impl Storage>,
+    auth: &Arc<Auth>,
+    events: &Arc<EventHub>,
+    marker_policy: MarkerPolicy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    get(Arc::clone(db)).or(post(Arc::clone(db)))
+    get(Arc::clone(db), Arc::clone(auth)).or(post(
+        Arc::clone(db),
+        Arc::clone(storage),
+        Arc::clone(auth),
+        Arc::clone(events),
+        marker_policy,
+    ))
 }
 
 fn get(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    auth: Arc<Auth>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String / "files")
         .and(warp::get())
+        .and(with_principal(auth))
+        .and(warp::query::<GetQuery>())
         .and(with_db(db))
         .and_then(on_get)
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct GetQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    order: Option<SortOrder>,
+
+    #[serde(rename = "markerID")]
+    marker_id: Option<String>,
+
+    // サーバで付けた作成時刻が createdFrom 以上 createdTo 未満 (UNIX時間のミリ秒)
+    #[serde(rename = "createdFrom")]
+    created_from: Option<EpochTime>,
+
+    #[serde(rename = "createdTo")]
+    created_to: Option<EpochTime>,
+}
+
 async fn on_get(
-    id: String,
-    db: Synced<impl Database>,
+    raw_id: String,
+    principal: Principal,
+    query: GetQuery,
+    db: Arc<impl Database>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let id = ClassID::from_str(id.as_str())
-        .map_err(IDParsingError)
-        .map_err(warp::reject::custom)?;
+    let id = readable_class(db.as_ref(), &raw_id, &principal).await?;
+    let limit = page_limit(query.limit);
+
+    let after = match &query.cursor {
+        Some(cursor) => Some(decode_cursor::<FileCursor>(cursor)?),
+        None => None,
+    };
 
     let resources = db
-        .lock()
-        .await
-        .get_files(&id)
+        .query_files(
+            &id,
+            &FileQuery {
+                marker_id: query.marker_id.map(ArMarkerID),
+                created_from: query.created_from,
+                created_to: query.created_to,
+                order: query.order.unwrap_or(SortOrder::Ascending),
+                after,
+                limit: limit + 1,
+            },
+        )
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
-    Ok(warp::reply::json(&resources))
+    Ok(paged_reply(resources, limit, |f| FileCursor::from(f)))
 }
 
 fn post(
-    db: Synced<impl Database>,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    auth: Arc<Auth>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!("classes" / String / "files")
         .and(warp::post())
+        .and(with_teacher(auth))
         .and(with_db(db))
-        .and(with_json_body())
+        .and(with_storage(storage))
+        .and(with_events(events))
+        .and(warp::any().map(move || marker_policy))
+        .and(warp::multipart::form().max_length(UPLOAD_SIZE_LIMIT))
         .and_then(on_post)
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "UploadMetadata")]
 struct PostRequestBody {
     #[serde(rename = "markerID")]
     marker_id: String,
@@ -59,37 +165,148 @@ struct PostRequestBody {
     resource_info: ResourceRequestBody,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "UploadResourceInfo")]
 struct ResourceRequestBody {
     #[serde(rename = "fileName")]
     file_name: String,
-    #[serde(rename = "createdAt")]
-    created_at: i64,
+
+    // 作成時刻はサーバで付けるので、クライアントの値は参考として残すだけ
+    // 前は createdAt という名前で必ず送っていたので、その名前でも受け付ける
+    #[serde(rename = "clientCreatedAt", alias = "createdAt", default)]
+    client_created_at: Option<EpochTime>,
+}
+
+impl Validate for PostRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("markerID", &self.marker_id);
+        errors.max_length("markerID", &self.marker_id, MAX_MARKER_ID_LENGTH);
+        errors.file_name("resourceInfo.fileName", &self.resource_info.file_name);
+
+        if let Some(client_created_at) = self.resource_info.client_created_at {
+            errors.timestamp("resourceInfo.clientCreatedAt", client_created_at);
+        }
+    }
+}
+
+// multipart/form-data で以下の2つのパートを受け取る
+// - "metadata": PostRequestBody の JSON
+// - "file": ファイルの中身
+struct UploadForm {
+    metadata: PostRequestBody,
+    content: Vec<u8>,
+}
+
+async fn read_part(part: Part) -> Result<Vec<u8>, FormError> {
+    part.stream()
+        .try_fold(Vec::new(), |mut content, mut chunk| async move {
+            content.extend_from_slice(&chunk.to_bytes());
+            Ok(content)
+        })
+        .await
+        .map_err(FormError::ReadFailed)
+}
+
+async fn read_upload_form(mut form: FormData) -> Result<UploadForm, FormError> {
+    let mut metadata = None;
+    let mut content = None;
+
+    while let Some(part) = form.try_next().await.map_err(FormError::ReadFailed)? {
+        match part.name() {
+            "metadata" => {
+                let raw = read_part(part).await?;
+                let parsed = serde_json::from_slice(&raw).map_err(FormError::InvalidMetadata)?;
+                metadata = Some(parsed);
+            }
+
+            "file" => content = Some(read_part(part).await?),
+
+            _ => {}
+        }
+    }
+
+    Ok(UploadForm {
+        metadata: metadata.ok_or(FormError::MissingPart("metadata"))?,
+        content: content.ok_or(FormError::MissingPart("file"))?,
+    })
 }
 
 async fn on_post(
     raw_id: String,
-    db: Synced<impl Database>,
-    body: PostRequestBody,
+    teacher_id: TeacherID,
+    db: Arc<impl Database>,
+    storage: Arc<impl Storage>,
+    events: Arc<EventHub>,
+    marker_policy: MarkerPolicy,
+    form: FormData,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let class_id = ClassID::from_str(raw_id.as_str())
-        .map_err(IDParsingError)
+    let class_id = owned_class(db.as_ref(), &raw_id, &teacher_id).await?.id;
+
+    let UploadForm {
+        metadata: body,
+        content,
+    } = read_upload_form(form)
+        .await
+        .map_err(FormParsingError)
+        .map_err(warp::reject::custom)?;
+
+    // metadata は multipart の中にあるので、with_json_body を通らない
+    let body = validate(body)
+        .map_err(ValidationError)
         .map_err(warp::reject::custom)?;
 
     let marker_id = ArMarkerID(body.marker_id);
-    let created_at = EpochTime(body.resource_info.created_at);
+    let mut retries = 0;
 
-    let file = File::new(&db, marker_id, body.resource_info.file_name, created_at)
+    // IDが同時に作られたファイルと被ったら、作り直す
+    let (file, replaced) = loop {
+        let file = File::new(
+            db.as_ref(),
+            marker_id.clone(),
+            body.resource_info.file_name.clone(),
+            body.resource_info.client_created_at,
+        )
         .await
         .map_err(ApiDBError)
         .map_err(warp::reject::custom)?;
 
-    db.lock()
-        .await
-        .add_new_file(&class_id, &file)
-        .await
-        .map_err(ApiDBError)
-        .map_err(warp::reject::custom)?;
+        // 同じIDの中身が既にあるときは上書きされずに断られるので、他のファイルの中身は壊れない
+        match storage.save(&file.id, &content).await {
+            Ok(()) => {}
+
+            Err(StorageError::AlreadyExists) if retries < CONFLICT_RETRIES => {
+                retries += 1;
+                continue;
+            }
+
+            Err(e) => return Err(warp::reject::custom(ApiStorageError(e))),
+        }
+
+        match db.add_new_file(&class_id, &file, marker_policy).await {
+            Ok(replaced) => break (file, replaced),
+
+            Err(e) => {
+                // 保存した中身はこのリクエストで作ったものなので、DBに登録できなければ消しておく
+                if let Err(e) = storage.delete(&file.id).await {
+                    log::error!("failed to delete orphaned content: {:?}", e);
+                }
+
+                match e {
+                    DatabaseError::Conflict if retries < CONFLICT_RETRIES => retries += 1,
+                    e => return Err(warp::reject::custom(ApiDBError(e))),
+                }
+            }
+        }
+    };
+
+    // 同じマーカーから外されたファイルは消えたものとして扱う
+    delete_contents(storage.as_ref(), &replaced).await;
+
+    for file in replaced {
+        events.publish(&class_id, EventKind::FileDeleted { file });
+    }
+
+    events.publish(&class_id, EventKind::FileAdded { file: file.clone() });
 
     Ok(warp::reply::json(&file))
 }
@@ -0,0 +1,118 @@
+use super::{with_db, with_json_body, ApiAuthError, ApiDBError};
+use crate::api::openapi::Operation;
+use crate::api::validation::{FieldErrors, Validate, MAX_NAME_LENGTH, MAX_PASSWORD_LENGTH};
+use crate::auth::{hash_password, AuthError};
+use crate::db::{Database, DatabaseError, CONFLICT_RETRIES};
+use crate::model::{Teacher, TeacherID};
+use schemars::gen::SchemaGenerator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::Filter;
+
+pub(super) fn describe(gen: &mut SchemaGenerator) -> Vec<Operation> {
+    vec![Operation::new("post", "/teachers", "先生を登録する")
+        .json_body::<PostRequestBody>(gen)
+        .json_response::<PostResponseBody>(201, "登録した先生", gen)]
+}
+
+pub(super) fn teachers(
+    db: &Arc<impl Database>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    post(Arc::clone(db))
+}
+
+fn post(
+    db: Arc<impl Database>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("teachers")
+        .and(warp::post())
+        .and(with_db(db))
+        .and(with_json_body())
+        .and_then(on_post)
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[schemars(rename = "RegisterTeacherRequest")]
+struct PostRequestBody {
+    name: String,
+    password: String,
+}
+
+impl Validate for PostRequestBody {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("name", &self.name);
+        errors.max_length("name", &self.name, MAX_NAME_LENGTH);
+        errors.required("password", &self.password);
+        errors.max_length("password", &self.password, MAX_PASSWORD_LENGTH);
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "RegisterTeacherResponse")]
+struct PostResponseBody {
+    id: TeacherID,
+    name: String,
+}
+
+async fn on_post(
+    db: Arc<impl Database>,
+    body: PostRequestBody,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let name_taken = db
+        .teacher_name_exists(&body.name)
+        .await
+        .map_err(ApiDBError)
+        .map_err(warp::reject::custom)?;
+
+    if name_taken {
+        return Err(warp::reject::custom(ApiAuthError(
+            AuthError::NameAlreadyTaken,
+        )));
+    }
+
+    let password_hash = hash_password(&body.password).await;
+    let mut retries = 0;
+
+    // 同じ名前で同時に登録されたら断り、IDが被っただけなら作り直す
+    let teacher = loop {
+        let teacher = Teacher::new(db.as_ref(), body.name.clone(), password_hash.clone())
+            .await
+            .map_err(ApiDBError)
+            .map_err(warp::reject::custom)?;
+
+        match db.save_new_teacher(&teacher).await {
+            Ok(()) => break teacher,
+
+            Err(DatabaseError::Conflict) => {
+                let name_taken = db
+                    .teacher_name_exists(&body.name)
+                    .await
+                    .map_err(ApiDBError)
+                    .map_err(warp::reject::custom)?;
+
+                if name_taken {
+                    return Err(warp::reject::custom(ApiAuthError(
+                        AuthError::NameAlreadyTaken,
+                    )));
+                }
+
+                if retries >= CONFLICT_RETRIES {
+                    return Err(warp::reject::custom(ApiDBError(DatabaseError::Conflict)));
+                }
+
+                retries += 1;
+            }
+
+            Err(e) => return Err(warp::reject::custom(ApiDBError(e))),
+        }
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&PostResponseBody {
+            id: teacher.id,
+            name: teacher.name,
+        }),
+        warp::http::StatusCode::CREATED,
+    ))
+}
@@ -0,0 +1,1026 @@
+// サーバと同じフィルタに warp::test でリクエストを送って、HTTPでの振る舞いを確かめる
+
+use super::*;
+use crate::db::mem::MemoryDB;
+use crate::storage::local::LocalStorage;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use uuid::Uuid;
+use warp::http::{Request, Response};
+use warp::hyper::body::{Body, Bytes, HttpBody};
+use warp::hyper::service::Service;
+use warp::test::RequestBuilder;
+
+const BOUNDARY: &str = "blackboard-test-boundary";
+
+// 中身の保存先はテストごとに別のディレクトリにして、終わったら消す
+struct Server {
+    storage_dir: PathBuf,
+}
+
+impl Server {
+    fn new() -> Self {
+        Server {
+            storage_dir: std::env::temp_dir().join(format!("blackboard-test-{}", Uuid::new_v4())),
+        }
+    }
+
+    fn filter(
+        &self,
+        marker_policy: MarkerPolicy,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
+        filter(
+            Arc::new(MemoryDB::new()),
+            Arc::new(LocalStorage::new(&self.storage_dir).unwrap()),
+            Arc::new(Auth::new(b"secret")),
+            Arc::new(EventHub::new()),
+            marker_policy,
+        )
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.storage_dir);
+    }
+}
+
+async fn send<F>(filter: &F, request: RequestBuilder) -> (StatusCode, Value)
+where
+    F: Filter<Error = Infallible> + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let response = request.reply(filter).await;
+    (response.status(), json_of(&response))
+}
+
+fn json_of(response: &Response<Bytes>) -> Value {
+    serde_json::from_slice(response.body()).unwrap_or(Value::Null)
+}
+
+fn get(path: &str, token: Option<&str>) -> RequestBuilder {
+    authorized(warp::test::request().method("GET").path(path), token)
+}
+
+fn with_json(method: &str, path: &str, token: Option<&str>, body: Value) -> RequestBuilder {
+    let request = warp::test::request().method(method).path(path).json(&body);
+    authorized(request, token)
+}
+
+fn authorized(request: RequestBuilder, token: Option<&str>) -> RequestBuilder {
+    match token {
+        Some(token) => request.header("authorization", format!("Bearer {}", token)),
+        None => request,
+    }
+}
+
+fn upload(path: &str, token: &str, metadata: Value, content: &[u8]) -> RequestBuilder {
+    warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("authorization", format!("Bearer {}", token))
+        .header(
+            "content-type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .body(multipart_body(metadata, content))
+}
+
+fn multipart_body(metadata: Value, content: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{m}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"upload\"\r\n\r\n",
+        b = BOUNDARY,
+        m = metadata,
+    )
+    .into_bytes();
+
+    body.extend_from_slice(content);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+// 先生を登録してログインし、トークンを返す
+async fn teacher_token<F>(filter: &F, name: &str) -> String
+where
+    F: Filter<Error = Infallible> + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let account = json!({ "name": name, "password": "password" });
+
+    let (status, _) = send(
+        filter,
+        with_json("POST", "/api/v1/teachers", None, account.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, body) = send(filter, with_json("POST", "/api/v1/login", None, account)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body["token"].as_str().unwrap().to_string()
+}
+
+async fn create_class<F>(filter: &F, token: &str, name: &str) -> Value
+where
+    F: Filter<Error = Infallible> + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let request = with_json(
+        "POST",
+        "/api/v1/classes",
+        Some(token),
+        json!({ "name": name }),
+    );
+    let (status, class) = send(filter, request).await;
+    assert_eq!(status, StatusCode::OK);
+
+    class
+}
+
+// 合言葉から生徒のトークンをもらう
+async fn student_token<F>(filter: &F, class: &Value) -> String
+where
+    F: Filter<Error = Infallible> + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let path = format!(
+        "/api/v1/class/by-pass/{}",
+        class["passPhrase"].as_str().unwrap()
+    );
+
+    let (status, body) = send(filter, get(&path, None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body["token"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn teachers_and_login() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+
+    teacher_token(&api, "t").await;
+
+    let account = json!({ "name": "t", "password": "password" });
+    let (status, body) = send(&api, with_json("POST", "/api/v1/teachers", None, account)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "name_already_taken");
+
+    let account = json!({ "name": "t", "password": "wrong" });
+    let (status, body) = send(&api, with_json("POST", "/api/v1/login", None, account)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "invalid_credentials");
+
+    let account = json!({ "name": "nobody", "password": "password" });
+    let (status, body) = send(&api, with_json("POST", "/api/v1/login", None, account)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "invalid_credentials");
+
+    let account = json!({ "name": " ", "password": "" });
+    let (status, body) = send(&api, with_json("POST", "/api/v1/teachers", None, account)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "validation_failed");
+    assert!(body["details"]["name"].is_array());
+    assert!(body["details"]["password"].is_array());
+}
+
+#[tokio::test]
+async fn class_crud() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+    let other = teacher_token(&api, "u").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let path = format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+
+    // 一覧は自分のクラスだけ
+    let (status, body) = send(&api, get("/api/v1/classes", None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "missing_token");
+
+    let (status, body) = send(&api, get("/api/v1/classes", Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], class["id"]);
+
+    let (status, body) = send(&api, get("/api/v1/classes", Some(&other))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+
+    // クラスは所有者か、そのクラスの生徒だけが読める
+    let (status, body) = send(&api, get(&path, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "missing_token");
+
+    let (status, body) = send(&api, get(&path, Some(&other))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "not_owner");
+
+    let (status, body) = send(&api, get(&path, Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, class);
+
+    let student = student_token(&api, &class).await;
+    let (status, body) = send(&api, get(&path, Some(&student))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], class["id"]);
+    assert_eq!(body["name"], class["name"]);
+    assert_eq!(body["files"], json!([]));
+    assert!(body.get("passPhrase").is_none());
+    assert!(body.get("owner").is_none());
+
+    // 名前の変更は所有者だけ
+    let rename = json!({ "name": "社会" });
+    let (status, body) = send(&api, with_json("PUT", &path, None, rename.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "missing_token");
+
+    let (status, body) = send(
+        &api,
+        with_json("PUT", &path, Some("broken"), rename.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "invalid_token");
+
+    let (status, body) = send(&api, with_json("PUT", &path, Some(&other), rename.clone())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "not_owner");
+
+    let (status, _) = send(&api, with_json("PUT", &path, Some(&token), rename)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (_, body) = send(&api, get(&path, Some(&token))).await;
+    assert_eq!(body["name"], "社会");
+
+    let (status, body) = send(
+        &api,
+        with_json("PUT", &path, Some(&token), json!({ "name": "" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "validation_failed");
+
+    let (status, body) = send(
+        &api,
+        with_json("PUT", &path, Some(&token), json!({ "title": "x" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_body");
+
+    // 削除
+    let delete = |token: &str| {
+        authorized(
+            warp::test::request().method("DELETE").path(&path),
+            Some(token),
+        )
+    };
+
+    let (status, body) = send(&api, delete(&other)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "not_owner");
+
+    let (status, body) = send(&api, delete(&token)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], class["id"]);
+
+    let (status, body) = send(&api, get(&path, Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "class_not_found");
+
+    let (status, body) = send(&api, delete(&token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "class_not_found");
+}
+
+#[tokio::test]
+async fn pass_phrase_lookup() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let other = create_class(&api, &token, "社会").await;
+
+    let path = format!(
+        "/api/v1/class/by-pass/{}",
+        class["passPhrase"].as_str().unwrap()
+    );
+
+    let (status, body) = send(&api, get(&path, None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["classID"], class["id"]);
+    assert_eq!(body["name"], "理科");
+
+    // 生徒のトークンはそのクラスを読むことだけできる
+    let student = body["token"].as_str().unwrap();
+    let class_path = |class: &Value| format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+    let files = |class: &Value| format!("{}/files", class_path(class));
+
+    let (status, body) = send(&api, get(&class_path(&class), Some(student))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("passPhrase").is_none());
+
+    let (status, body) = send(&api, get(&class_path(&other), Some(student))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "out_of_scope");
+
+    let (status, body) = send(&api, get("/api/v1/classes", Some(student))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "read_only_token");
+
+    let (status, body) = send(&api, get(&files(&class), Some(student))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+
+    let (status, body) = send(&api, get(&files(&other), Some(student))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "out_of_scope");
+
+    let (status, body) = send(&api, upload(&files(&class), student, json!({}), b"")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "read_only_token");
+
+    let (status, body) = send(&api, get("/api/v1/class/by-pass/no-such-pass", None)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "class_not_found");
+}
+
+#[tokio::test]
+async fn file_add_get_delete() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let files = format!("/api/v1/classes/{}/files", class["id"].as_str().unwrap());
+
+    let metadata = json!({ "markerID": "marker", "resourceInfo": { "fileName": "a.txt" } });
+    let (status, file) = send(&api, upload(&files, &token, metadata.clone(), b"hello")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(file["markerID"], "marker");
+    assert_eq!(file["resourceInfo"]["fileName"], "a.txt");
+
+    // 同じマーカーには付けられない
+    let (status, body) = send(&api, upload(&files, &token, metadata, b"again")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "marker_conflict");
+
+    let metadata = json!({ "markerID": "", "resourceInfo": { "fileName": "../a.txt" } });
+    let (status, body) = send(&api, upload(&files, &token, metadata, b"")).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["markerID"].is_array());
+    assert!(body["details"]["resourceInfo.fileName"].is_array());
+
+    let (status, body) = send(&api, upload(&files, &token, json!("broken"), b"")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_form");
+
+    // 読み出し
+    let (status, body) = send(&api, get(&files, Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([file.clone()]));
+
+    let path = format!("{}/{}", files, file["id"].as_str().unwrap());
+
+    let (status, body) = send(&api, get(&path, Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, file);
+
+    let response = get(&format!("{}/content", path), Some(&token))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/plain");
+    assert_eq!(response.body().as_ref(), b"hello");
+
+    let markers = format!(
+        "/api/v1/classes/{}/markers/marker",
+        class["id"].as_str().unwrap()
+    );
+    let (status, body) = send(&api, get(&markers, Some(&token))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([file.clone()]));
+
+    // 削除は一度だけ成功する
+    let delete = authorized(
+        warp::test::request().method("DELETE").path(&path),
+        Some(&token),
+    );
+    let (status, body) = send(&api, delete).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, file);
+
+    let delete = authorized(
+        warp::test::request().method("DELETE").path(&path),
+        Some(&token),
+    );
+    let (status, body) = send(&api, delete).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "file_not_found");
+
+    let (status, body) = send(&api, get(&format!("{}/content", path), Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "file_not_found");
+
+    let (_, body) = send(&api, get(&files, Some(&token))).await;
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn marker_policy_replace() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Replace);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let files = format!("/api/v1/classes/{}/files", class["id"].as_str().unwrap());
+    let metadata = json!({ "markerID": "marker", "resourceInfo": { "fileName": "a.txt" } });
+
+    let (_, first) = send(&api, upload(&files, &token, metadata.clone(), b"first")).await;
+    let (status, second) = send(&api, upload(&files, &token, metadata, b"second")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, body) = send(&api, get(&files, Some(&token))).await;
+    assert_eq!(body, json!([second]));
+
+    // 外されたファイルの中身も消える
+    let content = format!("{}/{}/content", files, first["id"].as_str().unwrap());
+    let (status, _) = send(&api, get(&content, Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // 付け替えたときも、付け替え先のマーカーのファイルを外して消す
+    let metadata = json!({ "markerID": "other", "resourceInfo": { "fileName": "b.txt" } });
+    let (_, third) = send(&api, upload(&files, &token, metadata, b"third")).await;
+
+    let path = format!("{}/{}", files, third["id"].as_str().unwrap());
+    let request = with_json(
+        "PATCH",
+        &path,
+        Some(&token),
+        json!({ "markerID": "marker" }),
+    );
+    let (status, updated) = send(&api, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["markerID"], "marker");
+
+    let (_, body) = send(&api, get(&files, Some(&token))).await;
+    assert_eq!(body, json!([updated]));
+
+    let content = format!("{}/{}/content", files, second["id"].as_str().unwrap());
+    let (status, _) = send(&api, get(&content, Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn file_update() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+    let other = teacher_token(&api, "u").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let student = student_token(&api, &class).await;
+    let files = format!("/api/v1/classes/{}/files", class["id"].as_str().unwrap());
+
+    let metadata = json!({ "markerID": "a", "resourceInfo": { "fileName": "a.txt" } });
+    let (_, first) = send(&api, upload(&files, &token, metadata, b"a")).await;
+
+    let metadata = json!({ "markerID": "b", "resourceInfo": { "fileName": "b.txt" } });
+    let (_, second) = send(&api, upload(&files, &token, metadata, b"b")).await;
+
+    let path = format!("{}/{}", files, second["id"].as_str().unwrap());
+    let patch = |token: &str, body: Value| with_json("PATCH", &path, Some(token), body);
+
+    // 送った項目だけ変わる
+    let (status, updated) = send(&api, patch(&token, json!({ "fileName": "c.png" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["id"], second["id"]);
+    assert_eq!(updated["markerID"], "b");
+    assert_eq!(updated["resourceInfo"]["fileName"], "c.png");
+
+    let (status, updated) = send(&api, patch(&token, json!({ "markerID": "c" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["markerID"], "c");
+    assert_eq!(updated["resourceInfo"]["fileName"], "c.png");
+
+    let (_, body) = send(&api, get(&path, Some(&token))).await;
+    assert_eq!(body, updated);
+
+    // 他のファイルが使っているマーカーには付け替えられない
+    let (status, body) = send(&api, patch(&token, json!({ "markerID": "a" }))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "marker_conflict");
+
+    // 一覧は同じミリ秒に作られたものをIDの順に並べるので、クラスに入っている順で比べる
+    let class_path = format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+    let (_, body) = send(&api, get(&class_path, Some(&token))).await;
+    assert_eq!(body["files"], json!([first, updated]));
+
+    let (status, body) = send(&api, patch(&token, json!({ "markerID": "" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "validation_failed");
+    assert!(body["details"]["markerID"].is_array());
+
+    let (status, body) = send(&api, patch(&token, json!({ "fileName": "../a" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(body["details"]["fileName"].is_array());
+
+    // 変えられるのは所有者だけ
+    let (status, body) = send(&api, patch(&other, json!({ "fileName": "d.png" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "not_owner");
+
+    let (status, body) = send(&api, patch(&student, json!({ "fileName": "d.png" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "read_only_token");
+
+    let missing = format!("{}/{}", files, Uuid::new_v4());
+    let request = with_json(
+        "PATCH",
+        &missing,
+        Some(&token),
+        json!({ "fileName": "d.png" }),
+    );
+    let (status, body) = send(&api, request).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "file_not_found");
+}
+
+// SSE は終わらないので、warp::test のように本文を全部待たずにレスポンスを受け取る
+async fn open_stream<F>(filter: &F, request: Request<Body>) -> Response<Body>
+where
+    F: Filter<Error = Infallible> + Clone + Send + 'static,
+    F::Extract: warp::Reply,
+{
+    warp::service(filter.clone()).call(request).await.unwrap()
+}
+
+// イベント1つ分 (空行まで) だけ読む
+async fn next_event(body: &mut Body) -> Option<String> {
+    let mut event = String::new();
+
+    while !event.contains("\n\n") {
+        let chunk = timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("no event within 5 seconds")?
+            .unwrap();
+
+        event.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+
+    Some(event)
+}
+
+fn event_id(event: &str) -> u64 {
+    let line = event.lines().find(|line| line.starts_with("id:"));
+    line.unwrap()[3..].parse().unwrap()
+}
+
+#[tokio::test]
+async fn class_events() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let other = create_class(&api, &token, "社会").await;
+    let student = student_token(&api, &class).await;
+
+    let class_path = format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+    let events = format!("{}/events/sse", class_path);
+
+    // 連番は起動した時刻から始まるので、一度繋いで確かめておく
+    let request = Request::get(&events)
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    let mut first = open_stream(&api, request).await.into_body();
+
+    let mut seqs = vec![];
+
+    for name in &["英語", "数学"] {
+        let rename = with_json("PUT", &class_path, Some(&token), json!({ "name": name }));
+        let (status, _) = send(&api, rename).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        seqs.push(event_id(&next_event(&mut first).await.unwrap()));
+    }
+
+    let seq = seqs[0];
+    assert_eq!(seqs[1], seq + 1);
+    drop(first);
+
+    // 読めない人には返さない
+    let (status, body) = send(&api, get(&events, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "missing_token");
+
+    let other_events = format!(
+        "/api/v1/classes/{}/events/sse",
+        other["id"].as_str().unwrap()
+    );
+    let (status, body) = send(&api, get(&other_events, Some(&student))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "out_of_scope");
+
+    // Last-Event-ID より後のイベントから送り直す
+    // EventSource はヘッダを付けられないので、トークンはクエリで渡す
+    let request = Request::get(format!("{}?token={}", events, student))
+        .header("last-event-id", seq.to_string())
+        .body(Body::empty())
+        .unwrap();
+
+    let response = open_stream(&api, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    let mut body = response.into_body();
+    let event = next_event(&mut body).await.unwrap();
+    assert_eq!(event_id(&event), seq + 1);
+    assert!(event.contains("event:classRenamed\n"), "{}", event);
+    assert!(event.contains("数学"), "{}", event);
+
+    // 繋いだ後のイベントも届き、クラスが消えたら終わる
+    let delete = authorized(
+        warp::test::request().method("DELETE").path(&class_path),
+        Some(&token),
+    );
+    let (status, _) = send(&api, delete).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let event = next_event(&mut body).await.unwrap();
+    assert_eq!(event_id(&event), seq + 2);
+    assert!(event.contains("event:classDeleted\n"), "{}", event);
+    assert_eq!(next_event(&mut body).await, None);
+
+    // 送り直せないほど古い (または新しすぎる) ときは、取り直してもらう
+    let request = Request::get(other_events)
+        .header("authorization", format!("Bearer {}", token))
+        .header("last-event-id", "5")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = open_stream(&api, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let event = next_event(&mut response.into_body()).await.unwrap();
+    assert!(event.contains("event:resync\n"), "{}", event);
+}
+
+#[tokio::test]
+async fn api_document() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+
+    let (status, body) = send(&api, get("/api/v1/openapi.json", None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["openapi"], "3.0.3");
+    assert_eq!(body["servers"][0]["url"], API_PREFIX);
+    assert!(body["paths"]["/classes"]["get"].is_object());
+
+    let response = get("/api/v1/docs", None).reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+
+    let html = std::str::from_utf8(response.body()).unwrap();
+    assert!(html.contains("openapi.json"));
+
+    // Swagger UI 本体は外から読み込まずに自分で配る
+    assert!(!html.contains("https://"));
+
+    let assets = vec![
+        ("/api/v1/docs/swagger-ui.css", "text/css"),
+        ("/api/v1/docs/swagger-ui-bundle.js", "application/javascript"),
+    ];
+
+    for (path, content_type) in assets {
+        let response = get(path, None).reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with(content_type));
+    }
+}
+
+#[tokio::test]
+async fn invalid_ids() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let class_path = format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+
+    let requests = vec![
+        get("/api/v1/classes/not-a-uuid", Some(&token)),
+        get("/api/v1/classes/not-a-uuid/files", Some(&token)),
+        get(&format!("{}/files/not-a-uuid", class_path), Some(&token)),
+        get(
+            &format!("{}/files/not-a-uuid/content", class_path),
+            Some(&token),
+        ),
+        authorized(
+            warp::test::request()
+                .method("DELETE")
+                .path(&format!("{}/files/not-a-uuid", class_path)),
+            Some(&token),
+        ),
+        with_json(
+            "PUT",
+            "/api/v1/classes/not-a-uuid",
+            Some(&token),
+            json!({ "name": "x" }),
+        ),
+    ];
+
+    for request in requests {
+        let (status, body) = send(&api, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_id");
+        assert!(body["details"].is_string());
+    }
+
+    // 形は正しくても無いIDは404
+    let missing = format!("/api/v1/classes/{}", Uuid::new_v4());
+    let (status, body) = send(&api, get(&missing, Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "class_not_found");
+
+    let missing = format!("{}/files/{}", class_path, Uuid::new_v4());
+    let (status, body) = send(&api, get(&missing, Some(&token))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "file_not_found");
+}
+
+// どのルートにも一致しないリクエストは、トークンやクラスの所有者を見る前に 404 か 405 になる
+#[tokio::test]
+async fn unmatched_class_routes() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+    let other = teacher_token(&api, "u").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let class_path = format!("/api/v1/classes/{}", class["id"].as_str().unwrap());
+
+    for token in &[None, Some(other.as_str())] {
+        let (status, body) = send(&api, get(&format!("{}/nope", class_path), *token)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+    }
+
+    let request = with_json(
+        "PUT",
+        &format!("{}/files", class_path),
+        Some(&other),
+        json!({ "name": "x" }),
+    );
+    let (status, body) = send(&api, request).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(body["code"], "method_not_allowed");
+}
+
+#[tokio::test]
+async fn oversized_body() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let name = "a".repeat(CONTENT_LENGTH_LIMIT as usize);
+    let request = with_json(
+        "POST",
+        "/api/v1/classes",
+        Some(&token),
+        json!({ "name": name }),
+    );
+
+    let (status, body) = send(&api, request).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["code"], "payload_too_large");
+
+    // 上限より小さくても長すぎる名前は断る
+    let name = "a".repeat(validation::MAX_NAME_LENGTH + 1);
+    let request = with_json(
+        "POST",
+        "/api/v1/classes",
+        Some(&token),
+        json!({ "name": name }),
+    );
+
+    let (status, body) = send(&api, request).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "validation_failed");
+}
+
+#[tokio::test]
+async fn cors_preflight() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+
+    let preflight = |method: &str, headers: &str| {
+        warp::test::request()
+            .method("OPTIONS")
+            .path("/api/v1/classes")
+            .header("origin", "https://example.com")
+            .header("access-control-request-method", method)
+            .header("access-control-request-headers", headers)
+    };
+
+    let response = preflight("PATCH", "authorization,content-type")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://example.com"
+    );
+
+    let allowed = response.headers()["access-control-allow-methods"]
+        .to_str()
+        .unwrap();
+    assert!(allowed.contains("PATCH"));
+
+    let (status, body) = send(&api, preflight("GET", "x-unknown")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "cors_forbidden");
+
+    // 普通のリクエストにも、ページ分けと移行用のヘッダを読めるように付ける
+    let response = get("/api/v1/classes", None)
+        .header("origin", "https://example.com")
+        .reply(&api)
+        .await;
+    let exposed = response.headers()["access-control-expose-headers"]
+        .to_str()
+        .unwrap();
+    assert!(exposed.contains(NEXT_CURSOR_HEADER));
+    assert!(exposed.contains("deprecation"));
+}
+
+#[tokio::test]
+async fn unprefixed_paths_are_deprecated() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let response = get("/api/v1/classes", Some(&token)).reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("deprecation").is_none());
+
+    let response = get("/classes", Some(&token)).reply(&api).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["deprecation"], "true");
+    assert_eq!(
+        response.headers()["link"],
+        "</api/v1/classes>; rel=\"successor-version\""
+    );
+
+    let response = get("/classes?limit=1&sort=name", Some(&token))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["link"],
+        "</api/v1/classes?limit=1&sort=name>; rel=\"successor-version\""
+    );
+}
+
+// 仕様書の操作ごとに、実際に送るリクエストを作る
+// 本文の要る操作はここに足す
+fn described_request(
+    method: &str,
+    path: &str,
+    uri: &str,
+    operation: &Value,
+    token: Option<&str>,
+) -> Request<Body> {
+    let body = match (method, path) {
+        ("post", "/classes") => Some(json!({ "name": "数学" })),
+        ("put", "/classes/{classID}") => Some(json!({ "name": "化学" })),
+        ("patch", "/classes/{classID}/files/{fileID}") => Some(json!({ "fileName": "b.txt" })),
+        ("post", "/teachers") => Some(json!({ "name": "u", "password": "password" })),
+        ("post", "/login") => Some(json!({ "name": "t", "password": "password" })),
+        _ => None,
+    };
+
+    let mut request = Request::builder()
+        .method(method.to_uppercase().as_str())
+        .uri(uri);
+
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+
+    // WebSocket に切り替わるものは、切り替えを頼むヘッダを付ける
+    if operation["responses"].get("101").is_some() {
+        request = request
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+    }
+
+    let body = match (method, path, body) {
+        ("post", "/classes/{classID}/files", _) => {
+            request = request.header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            );
+
+            let metadata = json!({ "markerID": "b", "resourceInfo": { "fileName": "b.txt" } });
+            multipart_body(metadata, b"b")
+        }
+
+        (_, _, Some(body)) => {
+            request = request.header("content-type", "application/json");
+            body.to_string().into_bytes()
+        }
+
+        _ => vec![],
+    };
+
+    assert_eq!(
+        !body.is_empty(),
+        operation.get("requestBody").is_some(),
+        "request body of {} {} does not match the document",
+        method,
+        path
+    );
+
+    // warp::test と違って Content-Length は付かないので、自分で付ける
+    if !body.is_empty() {
+        request = request.header("content-length", body.len());
+    }
+
+    request.body(Body::from(body)).unwrap()
+}
+
+// 仕様書に載っている操作が実際のルートにあり、載っているステータスで成功するか
+// トークンの要る操作は、無ければ断られるかも確かめる
+#[tokio::test]
+async fn api_document_matches_routes() {
+    let server = Server::new();
+    let api = server.filter(MarkerPolicy::Reject);
+    let token = teacher_token(&api, "t").await;
+
+    let class = create_class(&api, &token, "理科").await;
+    let class_id = class["id"].as_str().unwrap();
+    let files = format!("/api/v1/classes/{}/files", class_id);
+
+    let metadata = json!({ "markerID": "a", "resourceInfo": { "fileName": "a.txt" } });
+    let (_, file) = send(&api, upload(&files, &token, metadata, b"a")).await;
+
+    let (_, document) = send(&api, get("/api/v1/openapi.json", None)).await;
+
+    let mut operations = vec![];
+
+    for (path, item) in document["paths"].as_object().unwrap() {
+        for (method, operation) in item.as_object().unwrap() {
+            operations.push((method.clone(), path.clone(), operation.clone()));
+        }
+    }
+
+    // 消すのは最後にして、ファイルを消してからクラスを消す
+    operations.sort_by_key(|(method, path, _)| (method == "delete", std::cmp::Reverse(path.len())));
+
+    for (method, path, operation) in &operations {
+        let uri = format!(
+            "/api/v1{}",
+            path.replace("{classID}", class_id)
+                .replace("{fileID}", file["id"].as_str().unwrap())
+                .replace("{passPhrase}", class["passPhrase"].as_str().unwrap())
+                .replace("{markerID}", "a")
+        );
+
+        let authorized = operation.get("security").is_some();
+
+        if authorized {
+            let request = described_request(method, path, &uri, operation, None);
+            let status = open_stream(&api, request).await.status();
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{} {}", method, path);
+        }
+
+        let token = if authorized {
+            Some(token.as_str())
+        } else {
+            None
+        };
+        let request = described_request(method, path, &uri, operation, token);
+        let status = open_stream(&api, request).await.status();
+
+        assert!(
+            operation["responses"].get(status.as_str()).is_some(),
+            "{} {} returned {}, which is not documented",
+            method,
+            path,
+            status
+        );
+    }
+}
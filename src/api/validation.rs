@@ -0,0 +1,125 @@
+use crate::model::EpochTime;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub(super) const MAX_NAME_LENGTH: usize = 100;
+pub(super) const MAX_PASSWORD_LENGTH: usize = 128;
+pub(super) const MAX_MARKER_ID_LENGTH: usize = 100;
+pub(super) const MAX_FILE_NAME_LENGTH: usize = 255;
+
+// 端末の時計はずれていることがあるので、少し先の時刻までは受け付ける
+const ALLOWED_CLOCK_SKEW_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+// リクエストの本文を受け取ったら、使う前に中身を確かめる
+pub(super) trait Validate {
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+// 項目の名前 (JSONでの名前) ごとに、だめだった理由を並べる
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub(super) struct FieldErrors(BTreeMap<&'static str, Vec<String>>);
+
+impl FieldErrors {
+    fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.0.entry(field).or_default().push(message.into());
+    }
+
+    // 空白だけのものも空として扱う
+    pub(super) fn required(&mut self, field: &'static str, value: &str) {
+        if value.trim().is_empty() {
+            self.add(field, "must not be empty");
+        }
+    }
+
+    pub(super) fn max_length(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.chars().count() > max {
+            self.add(field, format!("must be at most {} characters", max));
+        }
+    }
+
+    // 保存先やダウンロード時の名前に使われるので、パスとして解釈されうるものは断る
+    pub(super) fn file_name(&mut self, field: &'static str, value: &str) {
+        self.required(field, value);
+        self.max_length(field, value, MAX_FILE_NAME_LENGTH);
+
+        if value
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+        {
+            self.add(field, "must not contain '/', '\\' or control characters");
+        }
+
+        if value == "." || value == ".." {
+            self.add(field, "must not be \".\" or \"..\"");
+        }
+    }
+
+    pub(super) fn timestamp(&mut self, field: &'static str, value: EpochTime) {
+        if value.millis() < 0 {
+            self.add(field, "must not be negative");
+        }
+
+        if value.millis() > EpochTime::now().millis() + ALLOWED_CLOCK_SKEW_MILLIS {
+            self.add(field, "must not be in the future");
+        }
+    }
+}
+
+pub(super) fn validate<T: Validate>(body: T) -> Result<T, FieldErrors> {
+    let mut errors = FieldErrors::default();
+    body.validate(&mut errors);
+
+    if errors.0.is_empty() {
+        Ok(body)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Upload {
+        file_name: &'static str,
+        created_at: EpochTime,
+    }
+
+    impl Validate for Upload {
+        fn validate(&self, errors: &mut FieldErrors) {
+            errors.file_name("fileName", self.file_name);
+            errors.timestamp("createdAt", self.created_at);
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_field() {
+        let upload = Upload {
+            file_name: "a.png",
+            created_at: EpochTime::now(),
+        };
+        assert!(validate(upload).is_ok());
+
+        let upload = Upload {
+            file_name: "../a.png",
+            created_at: EpochTime::from_millis(-1).unwrap(),
+        };
+        let errors = validate(upload).err().unwrap();
+
+        assert_eq!(errors.0["fileName"].len(), 1);
+        assert_eq!(errors.0["createdAt"], vec!["must not be negative"]);
+
+        let upload = Upload {
+            file_name: " ",
+            created_at: EpochTime::from_millis(
+                EpochTime::now().millis() + 2 * ALLOWED_CLOCK_SKEW_MILLIS,
+            )
+            .unwrap(),
+        };
+        let errors = validate(upload).err().unwrap();
+
+        assert_eq!(errors.0["fileName"], vec!["must not be empty"]);
+        assert_eq!(errors.0["createdAt"], vec!["must not be in the future"]);
+    }
+}
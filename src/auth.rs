@@ -0,0 +1,252 @@
+use crate::model::{ClassID, TeacherID};
+use chrono::{Duration, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::{hmac, pbkdf2};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use thiserror::Error;
+use tokio::task;
+
+const TEACHER_TOKEN_LIFETIME_HOURS: i64 = 24;
+const STUDENT_TOKEN_LIFETIME_HOURS: i64 = 2;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const HASH_PREFIX: &str = "pbkdf2-sha256";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuthError {
+    #[error("authorization header is missing")]
+    MissingToken,
+
+    #[error("token is malformed or its signature is invalid")]
+    InvalidToken,
+
+    #[error("token is expired")]
+    ExpiredToken,
+
+    #[error("name or password is wrong")]
+    InvalidCredentials,
+
+    #[error("specified teacher name is already taken")]
+    NameAlreadyTaken,
+
+    #[error("requester is not the owner of this class")]
+    NotOwner,
+
+    #[error("student token cannot be used for this operation")]
+    ReadOnlyToken,
+
+    #[error("student token is not issued for this class")]
+    OutOfScope,
+}
+
+// トークンの持ち主
+// 生徒のトークンは合言葉から発行されて、そのクラスの閲覧だけできる
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "role")]
+pub enum Principal {
+    #[serde(rename = "teacher")]
+    Teacher { id: TeacherID },
+
+    #[serde(rename = "student")]
+    Student { class: ClassID },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: Principal,
+    exp: i64,
+}
+
+// bearer tokenの発行と検証をする
+// トークンは "<base64(claims json)>.<base64(HMAC-SHA256)>" の形
+pub struct Auth {
+    key: hmac::Key,
+}
+
+impl Auth {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub fn issue_teacher_token(&self, teacher_id: &TeacherID) -> String {
+        let principal = Principal::Teacher {
+            id: teacher_id.clone(),
+        };
+
+        self.issue_token(principal, Duration::hours(TEACHER_TOKEN_LIFETIME_HOURS))
+    }
+
+    pub fn issue_student_token(&self, class_id: &ClassID) -> String {
+        let principal = Principal::Student {
+            class: class_id.clone(),
+        };
+
+        self.issue_token(principal, Duration::hours(STUDENT_TOKEN_LIFETIME_HOURS))
+    }
+
+    fn issue_token(&self, principal: Principal, lifetime: Duration) -> String {
+        let claims = Claims {
+            sub: principal,
+            exp: (Utc::now() + lifetime).timestamp(),
+        };
+
+        let payload = encode(&serde_json::to_vec(&claims).unwrap());
+        let signature = encode(hmac::sign(&self.key, payload.as_bytes()).as_ref());
+
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify_token(&self, token: &str) -> Result<Principal, AuthError> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next().ok_or(AuthError::InvalidToken)?;
+        let signature = parts.next().ok_or(AuthError::InvalidToken)?;
+        let signature = decode(signature)?;
+
+        hmac::verify(&self.key, payload.as_bytes(), &signature)
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let claims: Claims =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| AuthError::InvalidToken)?;
+
+        if claims.exp < Utc::now().timestamp() {
+            return Err(AuthError::ExpiredToken);
+        }
+
+        Ok(claims.sub)
+    }
+}
+
+// PBKDF2 は重いので、非同期のスレッドを止めないように別のスレッドで計算する
+pub async fn hash_password(password: &str) -> String {
+    let password = password.to_owned();
+
+    task::spawn_blocking(move || hash_password_blocking(&password))
+        .await
+        .expect("password hashing panicked")
+}
+
+pub async fn verify_password(password: &str, password_hash: &str) -> bool {
+    let password = password.to_owned();
+    let password_hash = password_hash.to_owned();
+
+    task::spawn_blocking(move || verify_password_blocking(&password, &password_hash))
+        .await
+        .expect("password verification panicked")
+}
+
+// 無い先生の名前でログインされたときに、ある先生と同じだけ時間をかけて照合するためのハッシュ
+// 塩も値も 0 で埋めたもので、実際のパスワードから作られることはない
+pub fn dummy_password_hash() -> String {
+    format!(
+        "{}${}${}${}",
+        HASH_PREFIX,
+        PBKDF2_ITERATIONS,
+        encode(&[0u8; SALT_LEN]),
+        encode(&[0u8; HASH_LEN])
+    )
+}
+
+fn hash_password_blocking(password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+
+    format!(
+        "{}${}${}${}",
+        HASH_PREFIX,
+        PBKDF2_ITERATIONS,
+        encode(&salt),
+        encode(&hash)
+    )
+}
+
+fn verify_password_blocking(password: &str, password_hash: &str) -> bool {
+    let parts = password_hash.split('$').collect::<Vec<_>>();
+
+    let (iterations, salt, hash) = match parts.as_slice() {
+        [HASH_PREFIX, iterations, salt, hash] => (iterations, salt, hash),
+        _ => return false,
+    };
+
+    let iterations = match iterations.parse().ok().and_then(NonZeroU32::new) {
+        Some(i) => i,
+        None => return false,
+    };
+
+    match (decode(salt), decode(hash)) {
+        (Ok(salt), Ok(hash)) => pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok(),
+
+        _ => false,
+    }
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, AuthError> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::InvalidToken)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn password_roundtrip() {
+        let hash = hash_password("correct horse").await;
+
+        assert!(verify_password("correct horse", &hash).await);
+        assert!(!verify_password("battery staple", &hash).await);
+        assert!(!verify_password("correct horse", "broken").await);
+        assert!(!verify_password("", &dummy_password_hash()).await);
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let auth = Auth::new(b"secret");
+        let teacher_id = TeacherID(Uuid::new_v4());
+        let token = auth.issue_teacher_token(&teacher_id);
+
+        assert_eq!(
+            auth.verify_token(&token),
+            Ok(Principal::Teacher { id: teacher_id })
+        );
+        assert_eq!(
+            Auth::new(b"another secret").verify_token(&token),
+            Err(AuthError::InvalidToken)
+        );
+        assert_eq!(auth.verify_token("garbage"), Err(AuthError::InvalidToken));
+
+        let class_id = ClassID(Uuid::new_v4());
+        let token = auth.issue_student_token(&class_id);
+
+        assert_eq!(
+            auth.verify_token(&token),
+            Ok(Principal::Student { class: class_id })
+        );
+    }
+}
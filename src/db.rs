@@ -1,43 +1,227 @@
+pub mod file;
 pub mod mem;
 pub mod mongo;
 
+#[cfg(test)]
+mod conformance;
+
 use crate::model::*;
 use async_trait::async_trait;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
 use thiserror::Error;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, JsonSchema)]
 pub struct SimpleClassInfo {
     pub name: String,
     pub id: ClassID,
 
     #[serde(rename = "passPhrase")]
     pub pass_phrase: PassPhrase,
+
+    #[serde(rename = "createdAt", default)]
+    pub created_at: EpochTime,
+
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: EpochTime,
+}
+
+impl From<&Class> for SimpleClassInfo {
+    fn from(class: &Class) -> Self {
+        SimpleClassInfo {
+            name: class.name.clone(),
+            id: class.id.clone(),
+            pass_phrase: class.pass_phrase.clone(),
+            created_at: class.created_at,
+            updated_at: class.updated_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum ClassSortKey {
+    #[serde(rename = "name")]
+    Name,
+
+    #[serde(rename = "createdAt")]
+    CreatedAt,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum SortOrder {
+    #[serde(rename = "asc")]
+    Ascending,
+
+    #[serde(rename = "desc")]
+    Descending,
+}
+
+// 前のページの最後のクラス
+// 並び替えのキーが同じクラスはIDの順に並べるので、IDも持っておく
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClassCursor {
+    pub name: String,
+    pub id: ClassID,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: EpochTime,
 }
 
+impl From<&SimpleClassInfo> for ClassCursor {
+    fn from(info: &SimpleClassInfo) -> Self {
+        ClassCursor {
+            name: info.name.clone(),
+            id: info.id.clone(),
+            created_at: info.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassQuery {
+    // この先生のクラスだけを返す
+    pub owner: Option<TeacherID>,
+
+    // 名前にこの文字列を含むクラスだけを返す (大文字小文字は区別しない)
+    pub name_contains: Option<String>,
+
+    pub sort: ClassSortKey,
+    pub order: SortOrder,
+
+    // これより後ろのクラスだけを返す
+    pub after: Option<ClassCursor>,
+
+    pub limit: usize,
+}
+
+// 前のページの最後のファイル
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileCursor {
+    pub id: FileID,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: EpochTime,
+}
+
+impl From<&File> for FileCursor {
+    fn from(file: &File) -> Self {
+        FileCursor {
+            id: file.id.clone(),
+            created_at: file.resource_info.created_at,
+        }
+    }
+}
+
+// ファイルは作成時刻 (同じならID) の順に並べる
+#[derive(Debug, Clone)]
+pub struct FileQuery {
+    pub marker_id: Option<ArMarkerID>,
+
+    // created_from 以上 created_to 未満のファイルだけを返す
+    pub created_from: Option<EpochTime>,
+    pub created_to: Option<EpochTime>,
+
+    pub order: SortOrder,
+    pub after: Option<FileCursor>,
+    pub limit: usize,
+}
+
+// None の項目は書き換えない
+#[derive(Debug, Clone, Default)]
+pub struct FileUpdate {
+    pub marker_id: Option<ArMarkerID>,
+    pub file_name: Option<String>,
+}
+
+// クラスの中で同じマーカーにファイルを紐付けようとしたときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerPolicy {
+    // MarkerConflict で断る
+    Reject,
+
+    // 今紐付いているファイルを外して置き換える
+    Replace,
+}
+
+// 作ったIDや合言葉が他と被って Conflict になったときに、作り直してやり直す回数
+// ID は生成時に *_exists で確かめているので、被るのは同時に作られたときくらい
+pub const CONFLICT_RETRIES: usize = 3;
+
 #[async_trait]
 pub trait Database: Send + Sync + 'static {
-    async fn get_all_classes(&self) -> Result<Vec<SimpleClassInfo>, DatabaseError>;
-    async fn save_new_class(&mut self, _: &Class) -> Result<(), DatabaseError>;
+    async fn query_classes(
+        &self,
+        query: &ClassQuery,
+    ) -> Result<Vec<SimpleClassInfo>, DatabaseError>;
+    // ID か合言葉が他のクラスと同じなら Conflict を返す
+    async fn save_new_class(&self, _: &Class) -> Result<(), DatabaseError>;
     async fn get_class_by_id(&self, class_id: &ClassID) -> Result<Class, DatabaseError>;
     async fn get_class_by_pass_phrase(
         &self,
         pass_phrase: &PassPhrase,
     ) -> Result<Class, DatabaseError>;
-    async fn rename_class(
-        &mut self,
-        class_id: &ClassID,
-        new_name: &str,
-    ) -> Result<(), DatabaseError>;
-    async fn delete_class(&mut self, class_id: &ClassID) -> Result<Class, DatabaseError>;
+    async fn rename_class(&self, class_id: &ClassID, new_name: &str) -> Result<(), DatabaseError>;
+    async fn delete_class(&self, class_id: &ClassID) -> Result<Class, DatabaseError>;
     async fn class_id_exists(&self, class_id: &ClassID) -> Result<bool, DatabaseError>;
     async fn pass_phrase_exists(&self, pass_phrase: &PassPhrase) -> Result<bool, DatabaseError>;
 
-    async fn get_files(&self, class_id: &ClassID) -> Result<Vec<File>, DatabaseError>;
-    async fn add_new_file(&mut self, class_id: &ClassID, file: &File) -> Result<(), DatabaseError>;
-    async fn get_file_by_id(&self, file_id: &FileID) -> Result<File, DatabaseError>;
-    async fn delete_file(&mut self, file_id: &FileID) -> Result<File, DatabaseError>;
+    async fn query_files(
+        &self,
+        class_id: &ClassID,
+        query: &FileQuery,
+    ) -> Result<Vec<File>, DatabaseError>;
+    // クラスの中で marker_id に紐付いたファイルを全部返す
+    async fn get_files_by_marker(
+        &self,
+        class_id: &ClassID,
+        marker_id: &ArMarkerID,
+    ) -> Result<Vec<File>, DatabaseError>;
+    // 同じマーカーのファイルが既にあったときは policy に従う
+    // 置き換えた場合は、外したファイルを返す
+    // ファイルのIDがどこかのクラスのファイルと同じなら Conflict を返す
+    async fn add_new_file(
+        &self,
+        class_id: &ClassID,
+        file: &File,
+        policy: MarkerPolicy,
+    ) -> Result<Vec<File>, DatabaseError>;
+
+    async fn get_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError>;
+    async fn delete_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError>;
+    // 付け替え先のマーカーが他のファイルに使われていれば policy に従う
+    // 変更後のファイルと、置き換えた場合は外したファイルを返す
+    async fn update_file(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        update: &FileUpdate,
+        policy: MarkerPolicy,
+    ) -> Result<(File, Vec<File>), DatabaseError>;
     async fn file_id_exists(&self, file_id: &FileID) -> Result<bool, DatabaseError>;
+
+    // ID か名前が他の先生と同じなら Conflict を返す
+    async fn save_new_teacher(&self, teacher: &Teacher) -> Result<(), DatabaseError>;
+    async fn get_teacher_by_name(&self, name: &str) -> Result<Teacher, DatabaseError>;
+    async fn teacher_id_exists(&self, teacher_id: &TeacherID) -> Result<bool, DatabaseError>;
+    async fn teacher_name_exists(&self, name: &str) -> Result<bool, DatabaseError>;
+
+    // サーバを止める直前に呼ばれる
+    // 書き出しきれていない変更があればここで書き出す
+    async fn shutdown(&self) -> Result<(), DatabaseError> {
+        Ok(())
+    }
 }
 
 #[derive(Error, Debug, PartialEq, Eq)]
@@ -50,6 +234,18 @@ pub enum DatabaseError {
     #[error("specified file id not found")]
     FileNotFound,
 
+    #[error("specified teacher not found")]
+    TeacherNotFound,
+
+    #[error("specified marker is already used by another file in the class")]
+    MarkerConflict,
+
+    #[error("id or pass phrase is already used")]
+    Conflict,
+
+    #[error("failed to write changes to disk")]
+    WriteFailed,
+
     #[error("connection error")]
     ConnectionError,
 
@@ -59,3 +255,16 @@ pub enum DatabaseError {
     #[error("deserialize failed, There are invalid entries in database")]
     DeserializeFailed,
 }
+
+// 書きかけのファイルが残らないように、一時ファイルに書いてから置き換える
+async fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut tmp = fs::File::create(&tmp_path).await?;
+    tmp.write_all(content).await?;
+    // 書き込みの失敗は sync_all では返ってこないので、先に flush する
+    tmp.flush().await?;
+    tmp.sync_all().await?;
+    fs::rename(&tmp_path, path).await
+}
@@ -0,0 +1,690 @@
+// どのバックエンドでも Database の各メソッドが同じように振る舞うかを確かめる
+// 他のテストや前回の実行で残ったデータがあっても通るように、
+// 名前はその都度作り、自分で作ったものだけを見る
+
+use crate::db::{
+    ClassCursor, ClassQuery, ClassSortKey, Database, DatabaseError, FileCursor, FileQuery,
+    FileUpdate, MarkerPolicy, SimpleClassInfo, SortOrder,
+};
+use crate::model::*;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::delay_for;
+use uuid::Uuid;
+
+pub(super) async fn check(db: &impl Database) {
+    check_classes(db).await;
+    check_class_queries(db).await;
+    check_files(db).await;
+    check_file_queries(db).await;
+    check_marker_policy(db).await;
+    check_field_like_markers(db).await;
+    check_updated_at(db).await;
+    check_teachers(db).await;
+}
+
+fn unique_name(prefix: &str) -> String {
+    format!("{}-{}", prefix, Uuid::new_v4())
+}
+
+async fn new_class(db: &impl Database, name: &str) -> Class {
+    let owner = TeacherID::new(db).await.unwrap();
+    let class = Class::new(db, name.into(), owner).await.unwrap();
+    db.save_new_class(&class).await.unwrap();
+    class
+}
+
+async fn new_file(db: &impl Database, marker: &str, name: &str) -> File {
+    File::new(db, ArMarkerID(marker.into()), name.into(), None)
+        .await
+        .unwrap()
+}
+
+async fn classes_of(db: &impl Database, owner: &TeacherID) -> Vec<SimpleClassInfo> {
+    let query = ClassQuery {
+        owner: Some(owner.clone()),
+        name_contains: None,
+        sort: ClassSortKey::CreatedAt,
+        order: SortOrder::Ascending,
+        after: None,
+        limit: 100,
+    };
+
+    db.query_classes(&query).await.unwrap()
+}
+
+async fn files_of(db: &impl Database, class_id: &ClassID) -> Result<Vec<File>, DatabaseError> {
+    db.get_class_by_id(class_id).await.map(|class| class.files)
+}
+
+async fn missing_class_id(db: &impl Database) -> ClassID {
+    ClassID::new(db).await.unwrap()
+}
+
+async fn missing_file_id(db: &impl Database) -> FileID {
+    FileID::new(db).await.unwrap()
+}
+
+async fn check_classes(db: &impl Database) {
+    let mut class = new_class(db, "理科").await;
+    let other = new_class(db, "社会").await;
+    let missing = missing_class_id(db).await;
+
+    // 読み出し
+    assert_eq!(db.get_class_by_id(&class.id).await, Ok(class.clone()));
+    assert_eq!(
+        db.get_class_by_pass_phrase(&other.pass_phrase).await,
+        Ok(other.clone())
+    );
+    assert_eq!(
+        db.get_class_by_id(&missing).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    let missing_pass_phrase = PassPhrase::new(db).await.unwrap();
+    assert_eq!(
+        db.get_class_by_pass_phrase(&missing_pass_phrase).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    assert_eq!(
+        classes_of(db, &class.owner).await,
+        vec![SimpleClassInfo::from(&class)]
+    );
+    assert_eq!(
+        classes_of(db, &other.owner).await,
+        vec![SimpleClassInfo::from(&other)]
+    );
+
+    // IDか合言葉が同じクラスは保存できない
+    assert_eq!(
+        db.save_new_class(&class).await,
+        Err(DatabaseError::Conflict)
+    );
+
+    let mut same_pass_phrase = Class::new(db, "数学".into(), class.owner.clone())
+        .await
+        .unwrap();
+    same_pass_phrase.pass_phrase = other.pass_phrase.clone();
+
+    assert_eq!(
+        db.save_new_class(&same_pass_phrase).await,
+        Err(DatabaseError::Conflict)
+    );
+    assert_eq!(db.class_id_exists(&same_pass_phrase.id).await, Ok(false));
+
+    // 存在確認
+    assert_eq!(db.class_id_exists(&class.id).await, Ok(true));
+    assert_eq!(db.class_id_exists(&missing).await, Ok(false));
+    assert_eq!(db.pass_phrase_exists(&class.pass_phrase).await, Ok(true));
+    assert_eq!(db.pass_phrase_exists(&missing_pass_phrase).await, Ok(false));
+
+    // 名前の変更は他のクラスに影響しない
+    db.rename_class(&class.id, "英語").await.unwrap();
+
+    let renamed = db.get_class_by_id(&class.id).await.unwrap();
+    assert!(renamed.updated_at >= class.updated_at);
+    class.name = "英語".into();
+    class.updated_at = renamed.updated_at;
+    assert_eq!(renamed, class);
+    assert_eq!(db.get_class_by_id(&other.id).await, Ok(other.clone()));
+
+    assert_eq!(
+        db.rename_class(&missing, "数学").await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(
+        db.get_class_by_id(&missing).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    // 削除は一度だけ成功する
+    assert_eq!(db.delete_class(&class.id).await, Ok(class.clone()));
+    assert_eq!(
+        db.delete_class(&class.id).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(
+        db.get_class_by_id(&class.id).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(db.class_id_exists(&class.id).await, Ok(false));
+    assert_eq!(db.pass_phrase_exists(&class.pass_phrase).await, Ok(false));
+    assert_eq!(db.get_class_by_id(&other.id).await, Ok(other.clone()));
+
+    db.delete_class(&other.id).await.unwrap();
+}
+
+async fn check_class_queries(db: &impl Database) {
+    // 他のクラスと混ざらないように、名前に共通の文字列を入れて絞り込む
+    let tag = unique_name("query");
+    let mut classes = vec![];
+
+    for name in &["b", "a", "c"] {
+        classes.push(new_class(db, &format!("{}-{}", tag, name)).await);
+    }
+
+    let mut query = ClassQuery {
+        owner: None,
+        name_contains: Some(tag.to_uppercase()),
+        sort: ClassSortKey::Name,
+        order: SortOrder::Ascending,
+        after: None,
+        limit: 2,
+    };
+
+    let names = |infos: Vec<SimpleClassInfo>| {
+        infos
+            .into_iter()
+            .map(|i| i.name.trim_start_matches(&tag).to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let page = db.query_classes(&query).await.unwrap();
+    query.after = Some(ClassCursor::from(&page[1]));
+    assert_eq!(names(page), vec!["-a", "-b"]);
+
+    let page = db.query_classes(&query).await.unwrap();
+    assert_eq!(names(page), vec!["-c"]);
+
+    query.after = None;
+    query.order = SortOrder::Descending;
+    query.limit = 10;
+
+    let page = db.query_classes(&query).await.unwrap();
+    assert_eq!(names(page), vec!["-c", "-b", "-a"]);
+
+    // 所有者で絞り込む
+    query.owner = Some(classes[1].owner.clone());
+    let page = db.query_classes(&query).await.unwrap();
+    assert_eq!(names(page), vec!["-a"]);
+
+    query.owner = Some(TeacherID::new(db).await.unwrap());
+    assert_eq!(db.query_classes(&query).await, Ok(vec![]));
+
+    query.owner = None;
+    query.name_contains = Some(unique_name("nothing"));
+    assert_eq!(db.query_classes(&query).await, Ok(vec![]));
+
+    for class in &classes {
+        db.delete_class(&class.id).await.unwrap();
+    }
+}
+
+async fn check_files(db: &impl Database) {
+    let mut class = new_class(db, "理科").await;
+    let other = new_class(db, "社会").await;
+    let missing_class = missing_class_id(db).await;
+    let missing_file = missing_file_id(db).await;
+
+    let mut files = vec![
+        new_file(db, "foo_marker", "foo.png").await,
+        new_file(db, "bar_marker", "bar.png").await,
+    ];
+
+    // 追加
+    for file in &files {
+        let replaced = db
+            .add_new_file(&class.id, file, MarkerPolicy::Reject)
+            .await
+            .unwrap();
+        assert!(replaced.is_empty());
+
+        class.files.push(file.clone());
+    }
+
+    let orphan = new_file(db, "foo_marker", "orphan.png").await;
+    assert_eq!(
+        db.add_new_file(&missing_class, &orphan, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(
+        db.add_new_file(&missing_class, &orphan, MarkerPolicy::Replace)
+            .await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(db.file_id_exists(&orphan.id).await, Ok(false));
+
+    // IDが同じファイルは、同じクラスにも他のクラスにも追加できない
+    let mut duplicate = new_file(db, "other_marker", "duplicate.png").await;
+    duplicate.id = files[0].id.clone();
+
+    for class_id in &[&class.id, &other.id] {
+        for policy in &[MarkerPolicy::Reject, MarkerPolicy::Replace] {
+            assert_eq!(
+                db.add_new_file(class_id, &duplicate, *policy).await,
+                Err(DatabaseError::Conflict)
+            );
+        }
+    }
+
+    // 読み出し (ファイルを足したので updatedAt は進んでいる)
+    let got = db.get_class_by_id(&class.id).await.unwrap();
+    assert!(got.updated_at >= class.updated_at);
+    class.updated_at = got.updated_at;
+    assert_eq!(got, class);
+    assert_eq!(files_of(db, &class.id).await, Ok(files.clone()));
+    assert_eq!(files_of(db, &other.id).await, Ok(vec![]));
+    assert_eq!(
+        files_of(db, &missing_class).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    assert_eq!(
+        db.get_files_by_marker(&class.id, &files[0].marker_id).await,
+        Ok(vec![files[0].clone()])
+    );
+    assert_eq!(
+        db.get_files_by_marker(&other.id, &files[0].marker_id).await,
+        Ok(vec![])
+    );
+    assert_eq!(
+        db.get_files_by_marker(&missing_class, &files[0].marker_id)
+            .await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    assert_eq!(db.file_id_exists(&files[0].id).await, Ok(true));
+    assert_eq!(db.file_id_exists(&missing_file).await, Ok(false));
+
+    assert_eq!(
+        db.get_file_in_class(&class.id, &files[1].id).await,
+        Ok(files[1].clone())
+    );
+    assert_eq!(
+        db.get_file_in_class(&other.id, &files[1].id).await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(
+        db.get_file_in_class(&class.id, &missing_file).await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(
+        db.get_file_in_class(&missing_class, &files[1].id).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    // 更新
+    let conflicting = FileUpdate {
+        marker_id: Some(files[0].marker_id.clone()),
+        file_name: None,
+    };
+    assert_eq!(
+        db.update_file(&class.id, &files[1].id, &conflicting, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::MarkerConflict)
+    );
+
+    // 自分のマーカーに付け直すのは衝突ではない
+    let same_marker = FileUpdate {
+        marker_id: Some(files[1].marker_id.clone()),
+        file_name: None,
+    };
+    let (updated, replaced) = db
+        .update_file(&class.id, &files[1].id, &same_marker, MarkerPolicy::Replace)
+        .await
+        .unwrap();
+    assert_eq!(updated.marker_id, files[1].marker_id);
+    assert_eq!(replaced, vec![]);
+
+    let update = FileUpdate {
+        marker_id: Some(ArMarkerID("baz_marker".into())),
+        file_name: Some("baz.png".into()),
+    };
+
+    let (updated, _) = db
+        .update_file(&class.id, &files[1].id, &update, MarkerPolicy::Reject)
+        .await
+        .unwrap();
+    assert!(updated.resource_info.updated_at >= files[1].resource_info.updated_at);
+
+    files[1].marker_id = ArMarkerID("baz_marker".into());
+    files[1].resource_info.filename = "baz.png".into();
+    files[1].resource_info.updated_at = updated.resource_info.updated_at;
+    assert_eq!(updated, files[1]);
+    assert_eq!(
+        db.get_file_in_class(&class.id, &files[1].id).await,
+        Ok(files[1].clone())
+    );
+
+    assert_eq!(
+        db.update_file(&other.id, &files[1].id, &update, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(
+        db.update_file(&class.id, &missing_file, &update, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(
+        db.update_file(&missing_class, &files[1].id, &update, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    // クラスを指定した削除は一度だけ成功する
+    assert_eq!(
+        db.delete_file_in_class(&other.id, &files[1].id).await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(
+        db.delete_file_in_class(&missing_class, &files[1].id).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(
+        db.delete_file_in_class(&class.id, &files[1].id).await,
+        Ok(files[1].clone())
+    );
+    assert_eq!(
+        db.delete_file_in_class(&class.id, &files[1].id).await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(db.file_id_exists(&files[1].id).await, Ok(false));
+    files.remove(1);
+
+    assert_eq!(
+        db.delete_file_in_class(&class.id, &files[0].id).await,
+        Ok(files[0].clone())
+    );
+    assert_eq!(
+        db.get_file_in_class(&class.id, &files[0].id).await,
+        Err(DatabaseError::FileNotFound)
+    );
+    assert_eq!(files_of(db, &class.id).await, Ok(vec![]));
+
+    // クラスを消すとファイルも読めなくなる
+    let file = new_file(db, "foo_marker", "foo.png").await;
+    db.add_new_file(&class.id, &file, MarkerPolicy::Reject)
+        .await
+        .unwrap();
+    db.delete_class(&class.id).await.unwrap();
+
+    assert_eq!(
+        db.get_file_in_class(&class.id, &file.id).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+    assert_eq!(db.file_id_exists(&file.id).await, Ok(false));
+
+    db.delete_class(&other.id).await.unwrap();
+}
+
+async fn check_file_queries(db: &impl Database) {
+    let class = new_class(db, "理科").await;
+    let missing = missing_class_id(db).await;
+
+    let mut files = vec![];
+
+    for (marker, name) in &[("a", "1.png"), ("b", "2.png"), ("a2", "3.png")] {
+        let file = new_file(db, marker, name).await;
+        db.add_new_file(&class.id, &file, MarkerPolicy::Reject)
+            .await
+            .unwrap();
+        files.push(file);
+    }
+
+    // 同じミリ秒に作られたものはIDの順に並ぶ
+    files.sort_by(|a, b| {
+        let a = FileCursor::from(a);
+        let b = FileCursor::from(b);
+        (a.created_at, a.id.0).cmp(&(b.created_at, b.id.0))
+    });
+
+    let mut query = FileQuery {
+        marker_id: None,
+        created_from: None,
+        created_to: None,
+        order: SortOrder::Ascending,
+        after: None,
+        limit: 2,
+    };
+
+    let page = db.query_files(&class.id, &query).await.unwrap();
+    assert_eq!(page, files[..2].to_vec());
+
+    query.after = Some(FileCursor::from(&page[1]));
+    let page = db.query_files(&class.id, &query).await.unwrap();
+    assert_eq!(page, files[2..].to_vec());
+
+    query.after = None;
+    query.order = SortOrder::Descending;
+    query.limit = 10;
+
+    let mut reversed = files.clone();
+    reversed.reverse();
+    assert_eq!(db.query_files(&class.id, &query).await, Ok(reversed));
+
+    // マーカーは完全に一致するものだけ
+    query.marker_id = Some(ArMarkerID("a".into()));
+    let page = db.query_files(&class.id, &query).await.unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].marker_id, ArMarkerID("a".into()));
+
+    // 作成時刻は from 以上 to 未満
+    let created_at = files[0].resource_info.created_at;
+    query.marker_id = None;
+    query.created_from = Some(created_at);
+    query.created_to = Some(created_at);
+    assert_eq!(db.query_files(&class.id, &query).await, Ok(vec![]));
+
+    query.created_to = None;
+    assert_eq!(db.query_files(&class.id, &query).await.unwrap().len(), 3);
+
+    assert_eq!(
+        db.query_files(&missing, &query).await,
+        Err(DatabaseError::ClassNotFound)
+    );
+
+    db.delete_class(&class.id).await.unwrap();
+}
+
+async fn check_marker_policy(db: &impl Database) {
+    let class = new_class(db, "理科").await;
+
+    let first = new_file(db, "m", "a.png").await;
+    let second = new_file(db, "m", "b.png").await;
+
+    assert_eq!(
+        db.add_new_file(&class.id, &first, MarkerPolicy::Reject)
+            .await,
+        Ok(vec![])
+    );
+    assert_eq!(
+        db.add_new_file(&class.id, &second, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::MarkerConflict)
+    );
+    assert_eq!(files_of(db, &class.id).await, Ok(vec![first.clone()]));
+
+    assert_eq!(
+        db.add_new_file(&class.id, &second, MarkerPolicy::Replace)
+            .await,
+        Ok(vec![first.clone()])
+    );
+    assert_eq!(files_of(db, &class.id).await, Ok(vec![second.clone()]));
+    assert_eq!(db.file_id_exists(&first.id).await, Ok(false));
+
+    // 付け替えでも同じように断るか置き換える
+    let third = new_file(db, "n", "c.png").await;
+    db.add_new_file(&class.id, &third, MarkerPolicy::Reject)
+        .await
+        .unwrap();
+
+    let update = FileUpdate {
+        marker_id: Some(second.marker_id.clone()),
+        file_name: Some("d.png".into()),
+    };
+    assert_eq!(
+        db.update_file(&class.id, &third.id, &update, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::MarkerConflict)
+    );
+    assert_eq!(
+        db.get_file_in_class(&class.id, &third.id).await,
+        Ok(third.clone())
+    );
+
+    let (updated, replaced) = db
+        .update_file(&class.id, &third.id, &update, MarkerPolicy::Replace)
+        .await
+        .unwrap();
+    assert_eq!(replaced, vec![second.clone()]);
+    assert_eq!(updated.marker_id, second.marker_id);
+    assert_eq!(updated.resource_info.filename, "d.png");
+    assert_eq!(files_of(db, &class.id).await, Ok(vec![updated]));
+    assert_eq!(db.file_id_exists(&second.id).await, Ok(false));
+
+    db.delete_class(&class.id).await.unwrap();
+}
+
+// "$" で始まるマーカーも、フィールドではなくただの文字列として比べる
+// クラスの name を指すものとして読まれると、どのファイルにも一致しなくなる
+async fn check_field_like_markers(db: &impl Database) {
+    let class = new_class(db, "理科").await;
+    let marker = ArMarkerID("$name".into());
+
+    let first = new_file(db, "$name", "a.png").await;
+    let other = new_file(db, "n", "b.png").await;
+
+    for file in &[&first, &other] {
+        db.add_new_file(&class.id, file, MarkerPolicy::Reject)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        db.get_files_by_marker(&class.id, &marker).await,
+        Ok(vec![first.clone()])
+    );
+
+    let query = FileQuery {
+        marker_id: Some(marker.clone()),
+        created_from: None,
+        created_to: None,
+        order: SortOrder::Ascending,
+        after: None,
+        limit: 10,
+    };
+    assert_eq!(
+        db.query_files(&class.id, &query).await,
+        Ok(vec![first.clone()])
+    );
+
+    let second = new_file(db, "$name", "c.png").await;
+    assert_eq!(
+        db.add_new_file(&class.id, &second, MarkerPolicy::Reject)
+            .await,
+        Err(DatabaseError::MarkerConflict)
+    );
+    assert_eq!(
+        db.add_new_file(&class.id, &second, MarkerPolicy::Replace)
+            .await,
+        Ok(vec![first])
+    );
+    assert_eq!(files_of(db, &class.id).await, Ok(vec![other, second]));
+
+    db.delete_class(&class.id).await.unwrap();
+}
+
+// change をした後で、クラスの updatedAt が change の前の時刻より進んでいるか
+async fn touches<T>(db: &impl Database, class_id: &ClassID, change: impl Future<Output = T>) -> T {
+    // 前の変更と同じミリ秒にならないように少し待つ
+    delay_for(Duration::from_millis(2)).await;
+    let before = EpochTime::now();
+
+    let result = change.await;
+
+    let class = db.get_class_by_id(class_id).await.unwrap();
+    assert!(class.updated_at >= before);
+
+    result
+}
+
+// ファイルを変えたときも、クラスが変わったことになる
+async fn check_updated_at(db: &impl Database) {
+    let class = new_class(db, "理科").await;
+    let id = &class.id;
+
+    let first = new_file(db, "m", "a.png").await;
+    let second = new_file(db, "m", "b.png").await;
+    let third = new_file(db, "n", "c.png").await;
+
+    touches(db, id, db.add_new_file(id, &first, MarkerPolicy::Reject))
+        .await
+        .unwrap();
+    touches(db, id, db.add_new_file(id, &second, MarkerPolicy::Replace))
+        .await
+        .unwrap();
+    touches(db, id, db.add_new_file(id, &third, MarkerPolicy::Reject))
+        .await
+        .unwrap();
+
+    let rename = FileUpdate {
+        marker_id: None,
+        file_name: Some("d.png".into()),
+    };
+    touches(
+        db,
+        id,
+        db.update_file(id, &third.id, &rename, MarkerPolicy::Reject),
+    )
+    .await
+    .unwrap();
+
+    let rebind = FileUpdate {
+        marker_id: Some(second.marker_id.clone()),
+        file_name: None,
+    };
+    touches(
+        db,
+        id,
+        db.update_file(id, &third.id, &rebind, MarkerPolicy::Replace),
+    )
+    .await
+    .unwrap();
+
+    touches(db, id, db.delete_file_in_class(id, &third.id))
+        .await
+        .unwrap();
+
+    db.delete_class(id).await.unwrap();
+}
+
+async fn check_teachers(db: &impl Database) {
+    let name = unique_name("teacher");
+    let teacher = Teacher::new(db, name.clone(), "hash".into()).await.unwrap();
+
+    assert_eq!(db.teacher_name_exists(&name).await, Ok(false));
+    assert_eq!(db.teacher_id_exists(&teacher.id).await, Ok(false));
+    assert_eq!(
+        db.get_teacher_by_name(&name).await,
+        Err(DatabaseError::TeacherNotFound)
+    );
+
+    db.save_new_teacher(&teacher).await.unwrap();
+
+    assert_eq!(db.teacher_name_exists(&name).await, Ok(true));
+    assert_eq!(db.teacher_id_exists(&teacher.id).await, Ok(true));
+    assert_eq!(db.get_teacher_by_name(&name).await, Ok(teacher.clone()));
+
+    // IDか名前が同じ先生は登録できない
+    let same_name = Teacher::new(db, name.clone(), "other".into())
+        .await
+        .unwrap();
+    assert_eq!(
+        db.save_new_teacher(&same_name).await,
+        Err(DatabaseError::Conflict)
+    );
+
+    let mut same_id = Teacher::new(db, unique_name("teacher"), "hash".into())
+        .await
+        .unwrap();
+    same_id.id = teacher.id.clone();
+    assert_eq!(
+        db.save_new_teacher(&same_id).await,
+        Err(DatabaseError::Conflict)
+    );
+    assert_eq!(db.teacher_name_exists(&same_id.name).await, Ok(false));
+    assert_eq!(db.get_teacher_by_name(&name).await, Ok(teacher));
+}
@@ -0,0 +1,681 @@
+use crate::db::mem::MemoryDB;
+use crate::db::{
+    write_atomically, ClassQuery, Database, DatabaseError, FileQuery, FileUpdate, MarkerPolicy,
+    SimpleClassInfo,
+};
+use crate::model::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const JOURNAL_FILE_NAME: &str = "blackboard.jsonl";
+
+// 今のスキーマのバージョン
+// レコードの形を変えたらここを上げて、MIGRATIONS に古い形から変換する関数を足す
+const SCHEMA_VERSION: u32 = 2;
+
+// MIGRATIONS[i] はバージョン i + 1 のレコードを i + 2 の形に直す
+const MIGRATIONS: &[fn(&mut Value)] = &[add_timestamps];
+
+// 追記したレコードがこれだけ溜まったら、今の中身だけを書き直してファイルを縮める
+const COMPACT_THRESHOLD: usize = 1000;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum FileDBError {
+    #[error("io error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("line {0} of the journal is broken")]
+    Corrupted(usize),
+
+    #[error("journal has schema version {0}, which is newer than this server supports")]
+    UnsupportedSchema(u32),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+}
+
+// ジャーナルの1行
+// 変更があったクラスや先生は、変更後の中身を丸ごと書く
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op")]
+enum Record {
+    #[serde(rename = "putClass")]
+    PutClass { class: Class },
+
+    #[serde(rename = "deleteClass")]
+    DeleteClass { id: ClassID },
+
+    #[serde(rename = "putTeacher")]
+    PutTeacher { teacher: Teacher },
+}
+
+struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    appended: usize,
+
+    // 最後に書き込みが成功したところまでの長さ
+    len: u64,
+
+    // 失敗した書き込みを切り詰められなかったので、次に追記する前に書き直す必要がある
+    broken: bool,
+}
+
+impl Journal {
+    async fn open(mem: &MemoryDB, path: PathBuf) -> Result<Journal, FileDBError> {
+        let file = compact(mem, &path).await?;
+        let len = file.metadata().await?.len();
+
+        Ok(Journal {
+            path,
+            file,
+            appended: 0,
+            len,
+            broken: false,
+        })
+    }
+
+    // 今の中身だけで書き直す
+    async fn rewrite(&mut self, mem: &MemoryDB) -> Result<(), FileDBError> {
+        let file = compact(mem, &self.path).await?;
+
+        self.len = file.metadata().await?.len();
+        self.file = file;
+        self.appended = 0;
+        self.broken = false;
+
+        Ok(())
+    }
+}
+
+// 外部のサーバ無しで使える、ファイルに書き出すDB
+// 中身は MemoryDB に持って読み込みはそちらに任せ、変更のたびにジャーナルへ追記する
+pub struct FileDB {
+    mem: MemoryDB,
+    journal: Mutex<Journal>,
+}
+
+impl FileDB {
+    pub async fn open(dir: impl AsRef<Path>) -> Result<FileDB, FileDBError> {
+        fs::create_dir_all(dir.as_ref()).await?;
+
+        let path = dir.as_ref().join(JOURNAL_FILE_NAME);
+        let mem = MemoryDB::new();
+
+        match fs::read_to_string(&path).await {
+            Ok(content) => replay(&mem, &content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // 古いスキーマのレコードや削除済みのレコードが残らないように、開くたびに書き直す
+        let journal = Journal::open(&mem, path).await?;
+
+        Ok(FileDB {
+            mem,
+            journal: Mutex::new(journal),
+        })
+    }
+
+    async fn append(&self, journal: &mut Journal, record: &Record) -> Result<(), DatabaseError> {
+        let mut line = serde_json::to_string(record).map_err(le(DatabaseError::SerializeFailed))?;
+        line.push('\n');
+
+        // tokio の File は書き込みの失敗を sync_data では返さないので、flush で受け取る
+        let written = async {
+            journal.file.write_all(line.as_bytes()).await?;
+            journal.file.flush().await?;
+            journal.file.sync_data().await
+        }
+        .await;
+
+        if let Err(e) = written {
+            // 書きかけの行や、書けたか分からない行が残ると、その後ろに追記した行ごと読めなくなったり
+            // 失敗したはずの変更が再起動後に戻ってきたりするので、書く前の長さに戻す
+            let truncated = async {
+                journal.file.set_len(journal.len).await?;
+                journal.file.sync_data().await
+            }
+            .await;
+
+            if let Err(e) = truncated {
+                log::error!("failed to truncate journal: {}", e);
+                journal.broken = true;
+            }
+
+            return Err(le(DatabaseError::WriteFailed)(e));
+        }
+
+        journal.len += line.len() as u64;
+        journal.appended += 1;
+
+        if journal.appended >= COMPACT_THRESHOLD {
+            // 縮められなくても追記はできているので、ログだけ残して続ける
+            if let Err(e) = journal.rewrite(&self.mem).await {
+                log::error!("failed to compact journal: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // 切り詰められなかった書き込みが残っていれば、メモリ上の今の中身で書き直す
+    // メモリ上の変更を戻した後か、次の変更の前に呼ぶ
+    async fn repair(&self, journal: &mut Journal) -> Result<(), DatabaseError> {
+        if journal.broken {
+            journal
+                .rewrite(&self.mem)
+                .await
+                .map_err(le(DatabaseError::WriteFailed))?;
+        }
+
+        Ok(())
+    }
+
+    // MemoryDB 上で変更してから、変更後のクラスをジャーナルに書く
+    // 書き込みに失敗したら、メモリ上の変更も元に戻してエラーにする
+    async fn write_class<T>(
+        &self,
+        class_id: &ClassID,
+        change: impl std::future::Future<Output = Result<T, DatabaseError>>,
+    ) -> Result<T, DatabaseError> {
+        let mut journal = self.journal.lock().await;
+        self.repair(&mut journal).await?;
+
+        let before = self.mem.get_class_by_id(class_id).await.ok();
+
+        let result = change.await?;
+
+        let record = match self.mem.get_class_by_id(class_id).await {
+            Ok(class) => Record::PutClass { class },
+            Err(_) => Record::DeleteClass {
+                id: class_id.clone(),
+            },
+        };
+
+        if let Err(e) = self.append(&mut journal, &record).await {
+            match before {
+                Some(class) => self.mem.put_class(class),
+                None => self.mem.remove_class(class_id),
+            }
+
+            // ここで直せなくても、次の変更の前にもう一度試す
+            let _ = self.repair(&mut journal).await;
+
+            return Err(e);
+        }
+
+        Ok(result)
+    }
+}
+
+// (Log Error)
+fn le<E, OE>(error: E) -> impl FnOnce(OE) -> E
+where
+    OE: std::fmt::Display,
+{
+    move |o| {
+        log::error!("FileDB Error: {}", &o);
+        error
+    }
+}
+
+fn replay(mem: &MemoryDB, content: &str) -> Result<(), FileDBError> {
+    let mut lines = content.lines().enumerate().peekable();
+
+    let version = match lines.next() {
+        Some((_, header)) => {
+            serde_json::from_str::<Header>(header)
+                .map_err(|_| FileDBError::Corrupted(1))?
+                .schema_version
+        }
+
+        None => SCHEMA_VERSION,
+    };
+
+    if version == 0 {
+        return Err(FileDBError::Corrupted(1));
+    }
+
+    if version > SCHEMA_VERSION {
+        return Err(FileDBError::UnsupportedSchema(version));
+    }
+
+    if version < SCHEMA_VERSION {
+        log::info!(
+            "migrating journal from schema version {} to {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    while let Some((index, line)) = lines.next() {
+        let record = serde_json::from_str::<Value>(line)
+            .ok()
+            .map(|mut value| {
+                for migrate in &MIGRATIONS[version as usize - 1..] {
+                    migrate(&mut value);
+                }
+
+                value
+            })
+            .and_then(|value| serde_json::from_value::<Record>(value).ok());
+
+        let record = match record {
+            Some(record) => record,
+
+            // 最後の行だけが壊れているのは、書き込みの途中で落ちた場合なので読み捨てる
+            None if lines.peek().is_none() => {
+                log::warn!("ignoring incomplete last line of the journal");
+                break;
+            }
+
+            None => return Err(FileDBError::Corrupted(index + 1)),
+        };
+
+        match record {
+            Record::PutClass { class } => mem.put_class(class),
+            Record::DeleteClass { id } => mem.remove_class(&id),
+            Record::PutTeacher { teacher } => mem.put_teacher(teacher),
+        }
+    }
+
+    Ok(())
+}
+
+// 1 -> 2: クラスとファイルにサーバ側の時刻を持たせる
+// それまでファイルの createdAt はクライアントが送ってきた値だったので clientCreatedAt に移す
+fn add_timestamps(record: &mut Value) {
+    let class = match record.get_mut("class") {
+        Some(Value::Object(class)) => class,
+        _ => return,
+    };
+
+    let created_at = class.get("createdAt").cloned().unwrap_or_else(|| 0.into());
+    class.insert("createdAt".into(), created_at.clone());
+    class.insert("updatedAt".into(), created_at);
+
+    let files = match class.get_mut("files") {
+        Some(Value::Array(files)) => files,
+        _ => return,
+    };
+
+    for info in files.iter_mut().filter_map(|f| f.get_mut("resourceInfo")) {
+        if let Value::Object(info) = info {
+            if let Some(client_created_at) = info.get("createdAt").cloned() {
+                info.insert("updatedAt".into(), client_created_at.clone());
+                info.insert("clientCreatedAt".into(), client_created_at);
+            }
+        }
+    }
+}
+
+// 今の中身だけを一時ファイルに書いてから置き換え、追記用に開き直す
+async fn compact(mem: &MemoryDB, path: &Path) -> Result<fs::File, FileDBError> {
+    let header = Header {
+        schema_version: SCHEMA_VERSION,
+    };
+
+    let teachers = mem
+        .all_teachers()
+        .into_iter()
+        .map(|teacher| Record::PutTeacher { teacher });
+
+    let classes = mem
+        .all_classes()
+        .into_iter()
+        .map(|class| Record::PutClass { class });
+
+    let mut content = serde_json::to_string(&header).unwrap();
+    content.push('\n');
+
+    for record in teachers.chain(classes) {
+        content.push_str(&serde_json::to_string(&record).unwrap());
+        content.push('\n');
+    }
+
+    write_atomically(path, content.as_bytes()).await?;
+
+    let file = OpenOptions::new().append(true).open(path).await?;
+    Ok(file)
+}
+
+#[async_trait]
+impl Database for FileDB {
+    async fn query_classes(
+        &self,
+        query: &ClassQuery,
+    ) -> Result<Vec<SimpleClassInfo>, DatabaseError> {
+        self.mem.query_classes(query).await
+    }
+
+    async fn save_new_class(&self, class: &Class) -> Result<(), DatabaseError> {
+        self.write_class(&class.id, self.mem.save_new_class(class))
+            .await
+    }
+
+    async fn get_class_by_id(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
+        self.mem.get_class_by_id(class_id).await
+    }
+
+    async fn get_class_by_pass_phrase(
+        &self,
+        pass_phrase: &PassPhrase,
+    ) -> Result<Class, DatabaseError> {
+        self.mem.get_class_by_pass_phrase(pass_phrase).await
+    }
+
+    async fn rename_class(&self, class_id: &ClassID, new_name: &str) -> Result<(), DatabaseError> {
+        self.write_class(class_id, self.mem.rename_class(class_id, new_name))
+            .await
+    }
+
+    async fn delete_class(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
+        self.write_class(class_id, self.mem.delete_class(class_id))
+            .await
+    }
+
+    async fn class_id_exists(&self, class_id: &ClassID) -> Result<bool, DatabaseError> {
+        self.mem.class_id_exists(class_id).await
+    }
+
+    async fn pass_phrase_exists(&self, pass_phrase: &PassPhrase) -> Result<bool, DatabaseError> {
+        self.mem.pass_phrase_exists(pass_phrase).await
+    }
+
+    async fn query_files(
+        &self,
+        class_id: &ClassID,
+        query: &FileQuery,
+    ) -> Result<Vec<File>, DatabaseError> {
+        self.mem.query_files(class_id, query).await
+    }
+
+    async fn get_files_by_marker(
+        &self,
+        class_id: &ClassID,
+        marker_id: &ArMarkerID,
+    ) -> Result<Vec<File>, DatabaseError> {
+        self.mem.get_files_by_marker(class_id, marker_id).await
+    }
+
+    async fn add_new_file(
+        &self,
+        class_id: &ClassID,
+        file: &File,
+        policy: MarkerPolicy,
+    ) -> Result<Vec<File>, DatabaseError> {
+        self.write_class(class_id, self.mem.add_new_file(class_id, file, policy))
+            .await
+    }
+
+    async fn get_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
+        self.mem.get_file_in_class(class_id, file_id).await
+    }
+
+    async fn delete_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
+        self.write_class(class_id, self.mem.delete_file_in_class(class_id, file_id))
+            .await
+    }
+
+    async fn update_file(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        update: &FileUpdate,
+        policy: MarkerPolicy,
+    ) -> Result<(File, Vec<File>), DatabaseError> {
+        self.write_class(
+            class_id,
+            self.mem.update_file(class_id, file_id, update, policy),
+        )
+        .await
+    }
+
+    async fn file_id_exists(&self, file_id: &FileID) -> Result<bool, DatabaseError> {
+        self.mem.file_id_exists(file_id).await
+    }
+
+    async fn save_new_teacher(&self, teacher: &Teacher) -> Result<(), DatabaseError> {
+        let mut journal = self.journal.lock().await;
+        self.repair(&mut journal).await?;
+
+        // 名前が被っていないかはメモリ上で確かめるので、先に入れてから書く
+        self.mem.save_new_teacher(teacher).await?;
+
+        let record = Record::PutTeacher {
+            teacher: teacher.clone(),
+        };
+
+        if let Err(e) = self.append(&mut journal, &record).await {
+            self.mem.remove_teacher(&teacher.id);
+            let _ = self.repair(&mut journal).await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn get_teacher_by_name(&self, name: &str) -> Result<Teacher, DatabaseError> {
+        self.mem.get_teacher_by_name(name).await
+    }
+
+    async fn teacher_id_exists(&self, teacher_id: &TeacherID) -> Result<bool, DatabaseError> {
+        self.mem.teacher_id_exists(teacher_id).await
+    }
+
+    async fn teacher_name_exists(&self, name: &str) -> Result<bool, DatabaseError> {
+        self.mem.teacher_name_exists(name).await
+    }
+
+    async fn shutdown(&self) -> Result<(), DatabaseError> {
+        // 書き込み中のものがあれば終わるのを待ってから、ジャーナルをディスクに同期する
+        let mut journal = self.journal.lock().await;
+
+        journal
+            .file
+            .sync_all()
+            .await
+            .map_err(le(DatabaseError::WriteFailed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("blackboard-test-{}", Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn reopen_restores_changes() {
+        let dir = temp_dir();
+
+        let (class, file) = {
+            let db = FileDB::open(&dir).await.unwrap();
+
+            let owner = TeacherID::new(&db).await.unwrap();
+            let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+            db.save_new_class(&class).await.unwrap();
+
+            let file = File::new(&db, ArMarkerID("marker".into()), "a.png".into(), None)
+                .await
+                .unwrap();
+
+            db.add_new_file(&class.id, &file, MarkerPolicy::Reject)
+                .await
+                .unwrap();
+            db.rename_class(&class.id, "社会").await.unwrap();
+
+            (class, file)
+        };
+
+        let db = FileDB::open(&dir).await.unwrap();
+        let got = db.get_class_by_id(&class.id).await.unwrap();
+
+        assert_eq!(got.name, "社会");
+        assert_eq!(got.files, vec![file]);
+
+        db.delete_class(&class.id).await.unwrap();
+
+        let db = FileDB::open(&dir).await.unwrap();
+        assert_eq!(
+            db.get_class_by_id(&class.id).await,
+            Err(DatabaseError::ClassNotFound)
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn conforms_to_database() {
+        let dir = temp_dir();
+
+        crate::db::conformance::check(&FileDB::open(&dir).await.unwrap()).await;
+
+        // ジャーナルから戻しても同じように動く
+        crate::db::conformance::check(&FileDB::open(&dir).await.unwrap()).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_append_leaves_no_trace() {
+        let dir = temp_dir();
+        let path = dir.join(JOURNAL_FILE_NAME);
+
+        let db = FileDB::open(&dir).await.unwrap();
+        let owner = TeacherID::new(&db).await.unwrap();
+        let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+        db.save_new_class(&class).await.unwrap();
+
+        // 書きかけの行を残したまま、書き込みも切り詰めもできなくする
+        {
+            let mut journal = db.journal.lock().await;
+
+            let mut torn = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut torn, b"{\"op\":\"putCl").unwrap();
+
+            journal.file = fs::File::open(&path).await.unwrap();
+        }
+
+        assert_eq!(
+            db.rename_class(&class.id, "社会").await,
+            Err(DatabaseError::WriteFailed)
+        );
+        assert_eq!(db.get_class_by_id(&class.id).await, Ok(class.clone()));
+
+        // 失敗した変更も書きかけの行も残っていない
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("社会"));
+        assert!(content.ends_with('\n'));
+        assert!(content
+            .lines()
+            .all(|line| serde_json::from_str::<Value>(line).is_ok()));
+
+        // その後の変更は読める
+        db.rename_class(&class.id, "英語").await.unwrap();
+
+        let reopened = FileDB::open(&dir).await.unwrap();
+        let got = reopened.get_class_by_id(&class.id).await.unwrap();
+        assert_eq!(got.name, "英語");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_checks_journal() {
+        let dir = temp_dir();
+        let path = dir.join(JOURNAL_FILE_NAME);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // 書きかけの最後の行は読み捨てる
+        std::fs::write(&path, "{\"schemaVersion\":1}\n{\"op\":\"putCl").unwrap();
+        FileDB::open(&dir).await.unwrap();
+
+        std::fs::write(&path, "{\"schemaVersion\":1}\nbroken\n{}\n").unwrap();
+        assert!(matches!(
+            FileDB::open(&dir).await,
+            Err(FileDBError::Corrupted(2))
+        ));
+
+        std::fs::write(&path, "{\"schemaVersion\":99}\n").unwrap();
+        assert!(matches!(
+            FileDB::open(&dir).await,
+            Err(FileDBError::UnsupportedSchema(99))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_migrates_old_journal() {
+        let dir = temp_dir();
+        let path = dir.join(JOURNAL_FILE_NAME);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let class_id = "6f3a6cb0-3b1a-4a55-9a57-8f2a0c2bde6e";
+        let file_id = "0d4bd35e-5f5b-4f0b-9e3c-9a0f7f5f2a11";
+        let owner = "b3c1f1d2-7a4e-4c55-8f0a-1c2d3e4f5a6b";
+
+        let record = serde_json::json!({
+            "op": "putClass",
+            "class": {
+                "name": "理科",
+                "id": class_id,
+                "passPhrase": "a-b-c",
+                "owner": owner,
+                "files": [{
+                    "id": file_id,
+                    "markerID": "m",
+                    "resourceInfo": { "fileName": "a.png", "createdAt": 1234 },
+                }],
+            },
+        });
+
+        std::fs::write(&path, format!("{{\"schemaVersion\":1}}\n{}\n", record)).unwrap();
+
+        let db = FileDB::open(&dir).await.unwrap();
+        let class = db
+            .get_class_by_id(&ClassID::from_str(class_id).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(class.created_at, EpochTime::default());
+        assert_eq!(class.updated_at, EpochTime::default());
+
+        let info = &class.files[0].resource_info;
+        let client_created_at = EpochTime::from_millis(1234);
+
+        assert_eq!(Some(info.created_at), client_created_at);
+        assert_eq!(Some(info.updated_at), client_created_at);
+        assert_eq!(info.client_created_at, client_created_at);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,123 +1,529 @@
-use crate::db::{Database, DatabaseError, SimpleClassInfo};
+use crate::db::{
+    write_atomically, ClassQuery, ClassSortKey, Database, DatabaseError, FileCursor, FileQuery,
+    FileUpdate, MarkerPolicy, SimpleClassInfo, SortOrder,
+};
 use crate::model::*;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs;
+use tokio::sync::Mutex;
 
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    #[error("io error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("snapshot is broken: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    classes: Vec<Class>,
+    teachers: Vec<Teacher>,
+}
+
+// 読み込みは並行してできるように RwLock で包む
+// ロックを持ったまま await することはないので std のものを使う
 pub struct MemoryDB {
-    inner: Vec<Class>,
+    inner: RwLock<Vec<Class>>,
+    teachers: RwLock<Vec<Teacher>>,
+
+    // スナップショットの書き出し先と、前回書き出してから変更があったか
+    snapshot_path: Option<PathBuf>,
+    dirty: AtomicBool,
+
+    // 書き出しは同じ一時ファイルを使うので、一度に一つしか走らせない
+    saving: Mutex<()>,
 }
 
 impl MemoryDB {
     pub fn new() -> Self {
-        Self { inner: vec![] }
+        Self::from_snapshot(Snapshot::default(), None)
+    }
+
+    fn from_snapshot(snapshot: Snapshot, snapshot_path: Option<PathBuf>) -> Self {
+        Self {
+            inner: RwLock::new(snapshot.classes),
+            teachers: RwLock::new(snapshot.teachers),
+            snapshot_path,
+            dirty: AtomicBool::new(false),
+            saving: Mutex::new(()),
+        }
+    }
+
+    // path にスナップショットがあれば読み込み、以降は save_snapshot でそこに書き出す
+    pub async fn with_snapshot(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let path = path.as_ref();
+
+        let snapshot = match fs::read(path).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self::from_snapshot(snapshot, Some(path.to_owned())))
+    }
+
+    // 前回から変更がなければ何もしない
+    // 他の書き出しが走っていれば、それが終わるのを待つ
+    pub async fn save_snapshot(&self) -> Result<(), SnapshotError> {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // dirty を見る前に取る
+        // 後にすると、走っている書き出しが終わる前に Ok を返してしまう
+        let _saving = self.saving.lock().await;
+
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let snapshot = Snapshot {
+            classes: self.all_classes(),
+            teachers: self.all_teachers(),
+        };
+
+        let result = async {
+            let content = serde_json::to_vec(&snapshot)?;
+            write_atomically(path, &content).await?;
+            Ok(())
+        }
+        .await;
+
+        // 書けなかった分は次の機会に書く
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+
+        result
+    }
+
+    // stop が終わったら戻る
+    // 書き出しの途中では止めないので、戻るのを待てば書きかけのまま終わることはない
+    pub async fn save_snapshot_periodically(
+        &self,
+        every: Duration,
+        stop: impl Future<Output = ()>,
+    ) {
+        let mut interval = tokio::time::interval(every);
+
+        // 最初の tick はすぐに返ってくるので読み飛ばす
+        interval.tick().await;
+
+        tokio::pin!(stop);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut stop => return,
+            }
+
+            if let Err(e) = self.save_snapshot().await {
+                log::error!("failed to save memory DB snapshot: {}", e);
+            }
+        }
+    }
+
+    fn classes(&self) -> RwLockReadGuard<'_, Vec<Class>> {
+        self.inner.read().unwrap()
+    }
+
+    // 書き込みロックを取ってから dirty を立てる
+    // 先に立てると、その間に書き出したスナップショットに変更が載らないまま dirty が消えることがある
+    fn classes_mut(&self) -> RwLockWriteGuard<'_, Vec<Class>> {
+        let guard = self.inner.write().unwrap();
+        self.dirty.store(true, Ordering::SeqCst);
+        guard
+    }
+
+    fn teachers(&self) -> RwLockReadGuard<'_, Vec<Teacher>> {
+        self.teachers.read().unwrap()
+    }
+
+    fn teachers_mut(&self) -> RwLockWriteGuard<'_, Vec<Teacher>> {
+        let guard = self.teachers.write().unwrap();
+        self.dirty.store(true, Ordering::SeqCst);
+        guard
+    }
+
+    // 以下は他のバックエンドがこれを土台にして中身を復元、書き戻しするためのもの
+
+    pub(super) fn all_classes(&self) -> Vec<Class> {
+        self.classes().clone()
+    }
+
+    pub(super) fn all_teachers(&self) -> Vec<Teacher> {
+        self.teachers().clone()
+    }
+
+    // 同じIDのクラスがあれば置き換える
+    pub(super) fn put_class(&self, class: Class) {
+        let mut classes = self.classes_mut();
+
+        match classes.iter_mut().find(|c| c.id == class.id) {
+            Some(c) => *c = class,
+            None => classes.push(class),
+        }
+    }
+
+    pub(super) fn remove_class(&self, class_id: &ClassID) {
+        self.classes_mut().retain(|c| c.id != *class_id);
+    }
+
+    pub(super) fn put_teacher(&self, teacher: Teacher) {
+        let mut teachers = self.teachers_mut();
+
+        match teachers.iter_mut().find(|t| t.id == teacher.id) {
+            Some(t) => *t = teacher,
+            None => teachers.push(teacher),
+        }
+    }
+
+    pub(super) fn remove_teacher(&self, teacher_id: &TeacherID) {
+        self.teachers_mut().retain(|t| t.id != *teacher_id);
+    }
+}
+
+// クラスの並び替えに使う値 (名前, 作成時刻, ID)
+struct ClassKey<'a>(&'a str, EpochTime, &'a ClassID);
+
+impl ClassKey<'_> {
+    // query の並び順で比べる。キーが同じならIDで比べる
+    fn compare(&self, other: &ClassKey<'_>, query: &ClassQuery) -> cmp::Ordering {
+        let ordering = match query.sort {
+            ClassSortKey::Name => self.0.cmp(other.0),
+            ClassSortKey::CreatedAt => self.1.cmp(&other.1),
+        }
+        .then_with(|| self.2 .0.cmp(&other.2 .0));
+
+        match query.order {
+            SortOrder::Ascending => ordering,
+            SortOrder::Descending => ordering.reverse(),
+        }
     }
 }
 
 #[async_trait]
 impl Database for MemoryDB {
-    async fn get_all_classes(&self) -> Result<Vec<SimpleClassInfo>, DatabaseError> {
-        let infos = self
-            .inner
+    async fn query_classes(
+        &self,
+        query: &ClassQuery,
+    ) -> Result<Vec<SimpleClassInfo>, DatabaseError> {
+        let needle = query.name_contains.as_ref().map(|n| n.to_lowercase());
+
+        let mut infos = self
+            .classes()
             .iter()
-            .map(|c| SimpleClassInfo {
-                name: c.name.clone(),
-                id: c.id.clone(),
-                pass_phrase: c.pass_phrase.clone(),
+            .filter(|c| match &query.owner {
+                Some(owner) => c.owner == *owner,
+                None => true,
             })
-            .collect();
+            .filter(|c| match &needle {
+                Some(needle) => c.name.to_lowercase().contains(needle),
+                None => true,
+            })
+            .filter(|c| match &query.after {
+                Some(after) => {
+                    let class = ClassKey(&c.name, c.created_at, &c.id);
+                    let after = ClassKey(&after.name, after.created_at, &after.id);
+
+                    class.compare(&after, query) == cmp::Ordering::Greater
+                }
+
+                None => true,
+            })
+            .map(SimpleClassInfo::from)
+            .collect::<Vec<_>>();
+
+        infos.sort_by(|a, b| {
+            let a = ClassKey(&a.name, a.created_at, &a.id);
+            let b = ClassKey(&b.name, b.created_at, &b.id);
+            a.compare(&b, query)
+        });
+
+        infos.truncate(query.limit);
 
         Ok(infos)
     }
 
-    async fn save_new_class(&mut self, c: &Class) -> Result<(), DatabaseError> {
-        self.inner.push(c.clone());
+    async fn save_new_class(&self, c: &Class) -> Result<(), DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        if classes
+            .iter()
+            .any(|class| class.id == c.id || class.pass_phrase == c.pass_phrase)
+        {
+            return Err(DatabaseError::Conflict);
+        }
+
+        classes.push(c.clone());
         Ok(())
     }
 
     async fn get_class_by_id(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
-        self.inner
+        self.classes()
             .iter()
             .find(|c| c.id == *class_id)
             .map_or_else(|| Err(DatabaseError::ClassNotFound), |c| Ok(c.clone()))
     }
 
-    async fn rename_class(
-        &mut self,
-        class_id: &ClassID,
-        new_name: &str,
-    ) -> Result<(), DatabaseError> {
-        self.inner
+    async fn rename_class(&self, class_id: &ClassID, new_name: &str) -> Result<(), DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        let class = classes
             .iter_mut()
             .find(|c| c.id == *class_id)
-            .ok_or_else(|| DatabaseError::ClassNotFound)?
-            .name = new_name.to_string();
+            .ok_or(DatabaseError::ClassNotFound)?;
+
+        class.name = new_name.to_string();
+        class.updated_at = EpochTime::now();
 
         Ok(())
     }
 
-    async fn delete_class(&mut self, class_id: &ClassID) -> Result<Class, DatabaseError> {
-        let index = self
-            .inner
+    async fn delete_class(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        let index = classes
             .iter()
             .position(|c| c.id == *class_id)
-            .ok_or_else(|| DatabaseError::ClassNotFound)?;
+            .ok_or(DatabaseError::ClassNotFound)?;
 
-        Ok(self.inner.remove(index))
+        Ok(classes.remove(index))
     }
 
-    async fn get_files(&self, class_id: &ClassID) -> Result<Vec<File>, DatabaseError> {
-        Ok(self.get_class_by_id(class_id).await?.files)
+    async fn query_files(
+        &self,
+        class_id: &ClassID,
+        query: &FileQuery,
+    ) -> Result<Vec<File>, DatabaseError> {
+        let compare = |a: &FileCursor, b: &FileCursor| {
+            let ordering = a
+                .created_at
+                .cmp(&b.created_at)
+                .then_with(|| a.id.0.cmp(&b.id.0));
+
+            match query.order {
+                SortOrder::Ascending => ordering,
+                SortOrder::Descending => ordering.reverse(),
+            }
+        };
+
+        let mut files = self
+            .classes()
+            .iter()
+            .find(|c| c.id == *class_id)
+            .ok_or(DatabaseError::ClassNotFound)?
+            .files
+            .iter()
+            .filter(|f| match &query.marker_id {
+                Some(marker_id) => f.marker_id == *marker_id,
+                None => true,
+            })
+            .filter(|f| match query.created_from {
+                Some(from) => f.resource_info.created_at >= from,
+                None => true,
+            })
+            .filter(|f| match query.created_to {
+                Some(to) => f.resource_info.created_at < to,
+                None => true,
+            })
+            .filter(|f| match &query.after {
+                Some(after) => compare(&FileCursor::from(*f), after) == cmp::Ordering::Greater,
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        files.sort_by(|a, b| compare(&FileCursor::from(a), &FileCursor::from(b)));
+        files.truncate(query.limit);
+
+        Ok(files)
     }
 
-    async fn add_new_file(&mut self, class_id: &ClassID, file: &File) -> Result<(), DatabaseError> {
-        self.inner
-            .iter_mut()
+    async fn get_files_by_marker(
+        &self,
+        class_id: &ClassID,
+        marker_id: &ArMarkerID,
+    ) -> Result<Vec<File>, DatabaseError> {
+        let files = self
+            .classes()
+            .iter()
             .find(|c| c.id == *class_id)
-            .ok_or_else(|| DatabaseError::ClassNotFound)?
+            .ok_or(DatabaseError::ClassNotFound)?
             .files
-            .push(file.clone());
+            .iter()
+            .filter(|f| f.marker_id == *marker_id)
+            .cloned()
+            .collect();
 
-        Ok(())
+        Ok(files)
     }
 
-    async fn get_file_by_id(&self, file_id: &FileID) -> Result<File, DatabaseError> {
-        self.inner
+    async fn add_new_file(
+        &self,
+        class_id: &ClassID,
+        file: &File,
+        policy: MarkerPolicy,
+    ) -> Result<Vec<File>, DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        if !classes.iter().any(|c| c.id == *class_id) {
+            return Err(DatabaseError::ClassNotFound);
+        }
+
+        if classes
             .iter()
             .flat_map(|c| c.files.iter())
+            .any(|f| f.id == file.id)
+        {
+            return Err(DatabaseError::Conflict);
+        }
+
+        let class = classes
+            .iter_mut()
+            .find(|c| c.id == *class_id)
+            .ok_or(DatabaseError::ClassNotFound)?;
+        let files = &mut class.files;
+
+        // 断るときはファイルの並びを変えないように、取り出す前に調べる
+        if policy == MarkerPolicy::Reject && files.iter().any(|f| f.marker_id == file.marker_id) {
+            return Err(DatabaseError::MarkerConflict);
+        }
+
+        let (replaced, rest) = files
+            .drain(..)
+            .partition::<Vec<_>, _>(|f| f.marker_id == file.marker_id);
+
+        *files = rest;
+        files.push(file.clone());
+        class.updated_at = EpochTime::now();
+
+        Ok(replaced)
+    }
+
+    async fn get_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
+        self.classes()
+            .iter()
+            .find(|c| c.id == *class_id)
+            .ok_or(DatabaseError::ClassNotFound)?
+            .files
+            .iter()
             .find(|f| f.id == *file_id)
-            .ok_or_else(|| DatabaseError::FileNotFound)
-            .map(|f| f.clone())
+            .ok_or(DatabaseError::FileNotFound)
+            .cloned()
+    }
+
+    async fn delete_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        let class = classes
+            .iter_mut()
+            .find(|c| c.id == *class_id)
+            .ok_or(DatabaseError::ClassNotFound)?;
+        let files = &mut class.files;
+
+        let index = files
+            .iter()
+            .position(|f| f.id == *file_id)
+            .ok_or(DatabaseError::FileNotFound)?;
+
+        class.updated_at = EpochTime::now();
+        Ok(files.remove(index))
     }
 
-    async fn delete_file(&mut self, file_id: &FileID) -> Result<File, DatabaseError> {
-        for class in &mut self.inner {
-            if let Some(index) = class.files.iter().position(|f| f.id == *file_id) {
-                return Ok(class.files.remove(index));
+    async fn update_file(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        update: &FileUpdate,
+        policy: MarkerPolicy,
+    ) -> Result<(File, Vec<File>), DatabaseError> {
+        let mut classes = self.classes_mut();
+
+        let class = classes
+            .iter_mut()
+            .find(|c| c.id == *class_id)
+            .ok_or(DatabaseError::ClassNotFound)?;
+        let files = &mut class.files;
+
+        if !files.iter().any(|f| f.id == *file_id) {
+            return Err(DatabaseError::FileNotFound);
+        }
+
+        let mut replaced = vec![];
+
+        if let Some(marker_id) = &update.marker_id {
+            let taken = |f: &File| f.marker_id == *marker_id && f.id != *file_id;
+
+            if policy == MarkerPolicy::Reject && files.iter().any(taken) {
+                return Err(DatabaseError::MarkerConflict);
             }
+
+            let (taken, rest) = files.drain(..).partition::<Vec<_>, _>(taken);
+            *files = rest;
+            replaced = taken;
+        }
+
+        let file = files
+            .iter_mut()
+            .find(|f| f.id == *file_id)
+            .ok_or(DatabaseError::FileNotFound)?;
+
+        if let Some(marker_id) = &update.marker_id {
+            file.marker_id = marker_id.clone();
         }
 
-        Err(DatabaseError::FileNotFound)
+        if let Some(file_name) = &update.file_name {
+            file.resource_info.filename = file_name.clone();
+        }
+
+        file.resource_info.updated_at = EpochTime::now();
+        class.updated_at = file.resource_info.updated_at;
+
+        Ok((file.clone(), replaced))
     }
 
     async fn get_class_by_pass_phrase(
         &self,
         pass_phrase: &PassPhrase,
     ) -> Result<Class, DatabaseError> {
-        self.inner
+        self.classes()
             .iter()
             .find(|c| c.pass_phrase == *pass_phrase)
             .map_or_else(|| Err(DatabaseError::ClassNotFound), |c| Ok(c.clone()))
     }
 
     async fn class_id_exists(&self, id: &ClassID) -> Result<bool, DatabaseError> {
-        Ok(self.inner.iter().any(|c| c.id == *id))
+        Ok(self.classes().iter().any(|c| c.id == *id))
     }
 
     async fn pass_phrase_exists(&self, pass_phrase: &PassPhrase) -> Result<bool, DatabaseError> {
-        Ok(self.inner.iter().any(|c| c.pass_phrase == *pass_phrase))
+        Ok(self.classes().iter().any(|c| c.pass_phrase == *pass_phrase))
     }
 
     async fn file_id_exists(&self, file_id: &FileID) -> Result<bool, DatabaseError> {
-        for class in &self.inner {
+        for class in self.classes().iter() {
             if class.files.iter().any(|f| f.id == *file_id) {
                 return Ok(true);
             }
@@ -125,4 +531,236 @@ impl Database for MemoryDB {
 
         Ok(false)
     }
+
+    async fn save_new_teacher(&self, teacher: &Teacher) -> Result<(), DatabaseError> {
+        let mut teachers = self.teachers_mut();
+
+        if teachers
+            .iter()
+            .any(|t| t.id == teacher.id || t.name == teacher.name)
+        {
+            return Err(DatabaseError::Conflict);
+        }
+
+        teachers.push(teacher.clone());
+        Ok(())
+    }
+
+    async fn get_teacher_by_name(&self, name: &str) -> Result<Teacher, DatabaseError> {
+        self.teachers()
+            .iter()
+            .find(|t| t.name == name)
+            .map_or_else(|| Err(DatabaseError::TeacherNotFound), |t| Ok(t.clone()))
+    }
+
+    async fn teacher_id_exists(&self, teacher_id: &TeacherID) -> Result<bool, DatabaseError> {
+        Ok(self.teachers().iter().any(|t| t.id == *teacher_id))
+    }
+
+    async fn teacher_name_exists(&self, name: &str) -> Result<bool, DatabaseError> {
+        Ok(self.teachers().iter().any(|t| t.name == name))
+    }
+
+    async fn shutdown(&self) -> Result<(), DatabaseError> {
+        self.save_snapshot().await.map_err(|e| {
+            log::error!("failed to save memory DB snapshot: {}", e);
+            DatabaseError::WriteFailed
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::ClassCursor;
+    use std::sync::Arc;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn snapshot_roundtrip() {
+        let path = std::env::temp_dir().join(format!("blackboard-test-{}.json", Uuid::new_v4()));
+
+        let db = MemoryDB::with_snapshot(&path).await.unwrap();
+        let owner = TeacherID::new(&db).await.unwrap();
+        let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+        db.save_new_class(&class).await.unwrap();
+        db.save_snapshot().await.unwrap();
+
+        // 変更がなければ書き直さない
+        std::fs::remove_file(&path).unwrap();
+        db.save_snapshot().await.unwrap();
+        assert!(!path.exists());
+
+        db.rename_class(&class.id, "社会").await.unwrap();
+        db.save_snapshot().await.unwrap();
+
+        let db = MemoryDB::with_snapshot(&path).await.unwrap();
+        let got = db.get_class_by_id(&class.id).await.unwrap();
+        assert_eq!(got.name, "社会");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overlapping_saves_wait_for_each_other() {
+        let path = std::env::temp_dir().join(format!("blackboard-test-{}.json", Uuid::new_v4()));
+
+        let db = MemoryDB::with_snapshot(&path).await.unwrap();
+        let owner = TeacherID::new(&db).await.unwrap();
+        let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+        db.save_new_class(&class).await.unwrap();
+
+        // 書き出しの途中で変更して、もう一つ書き出しを始める
+        for i in 0..20 {
+            let name = format!("社会{}", i);
+
+            let (first, second) = futures::join!(db.save_snapshot(), async {
+                db.rename_class(&class.id, &name).await.unwrap();
+                db.save_snapshot().await
+            });
+
+            first.unwrap();
+            second.unwrap();
+
+            let saved = MemoryDB::with_snapshot(&path).await.unwrap();
+            let got = saved.get_class_by_id(&class.id).await.unwrap();
+            assert_eq!(got.name, name);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_overlapping_periodic_saves() {
+        let path = std::env::temp_dir().join(format!("blackboard-test-{}.json", Uuid::new_v4()));
+
+        let db = Arc::new(MemoryDB::with_snapshot(&path).await.unwrap());
+        let owner = TeacherID::new(db.as_ref()).await.unwrap();
+        let class = Class::new(db.as_ref(), "理科".into(), owner).await.unwrap();
+        db.save_new_class(&class).await.unwrap();
+
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let periodic_db = Arc::clone(&db);
+        let periodic = tokio::spawn(async move {
+            let stop = async {
+                stop_rx.await.ok();
+            };
+
+            periodic_db
+                .save_snapshot_periodically(Duration::from_millis(1), stop)
+                .await
+        });
+
+        // 定期的な書き出しが走っている間に、変更と最後の書き出しを繰り返す
+        for i in 0..20 {
+            db.rename_class(&class.id, &format!("社会{}", i))
+                .await
+                .unwrap();
+
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            db.shutdown().await.unwrap();
+        }
+
+        db.rename_class(&class.id, "英語").await.unwrap();
+
+        // main と同じく、定期的な書き出しを止めてから閉じる
+        stop_tx.send(()).unwrap();
+        periodic.await.unwrap();
+        db.shutdown().await.unwrap();
+
+        let saved = MemoryDB::with_snapshot(&path).await.unwrap();
+        let got = saved.get_class_by_id(&class.id).await.unwrap();
+        assert_eq!(got.name, "英語");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn query_classes_pages_in_order() {
+        let db = MemoryDB::new();
+        let owner = TeacherID::new(&db).await.unwrap();
+
+        for name in &["b", "a", "c", "ab"] {
+            let class = Class::new(&db, name.to_string(), owner.clone())
+                .await
+                .unwrap();
+
+            db.save_new_class(&class).await.unwrap();
+        }
+
+        let mut query = ClassQuery {
+            owner: None,
+            name_contains: None,
+            sort: ClassSortKey::Name,
+            order: SortOrder::Descending,
+            after: None,
+            limit: 3,
+        };
+
+        let names =
+            |infos: Vec<SimpleClassInfo>| infos.into_iter().map(|i| i.name).collect::<Vec<_>>();
+
+        let page = db.query_classes(&query).await.unwrap();
+        query.after = Some(ClassCursor::from(&page[1]));
+        assert_eq!(names(page), vec!["c", "b", "ab"]);
+
+        let page = db.query_classes(&query).await.unwrap();
+        assert_eq!(names(page), vec!["ab", "a"]);
+
+        query.after = None;
+        query.name_contains = Some("A".into());
+        query.order = SortOrder::Ascending;
+
+        let page = db.query_classes(&query).await.unwrap();
+        assert_eq!(names(page), vec!["a", "ab"]);
+    }
+
+    #[tokio::test]
+    async fn conforms_to_database() {
+        crate::db::conformance::check(&MemoryDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn add_new_file_follows_marker_policy() {
+        let db = MemoryDB::new();
+        let owner = TeacherID::new(&db).await.unwrap();
+        let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+        db.save_new_class(&class).await.unwrap();
+
+        let new_file =
+            |marker: &str, name: &str| File::new(&db, ArMarkerID(marker.into()), name.into(), None);
+
+        let first = new_file("m", "a.png").await.unwrap();
+        let other = new_file("n", "c.png").await.unwrap();
+        let second = new_file("m", "b.png").await.unwrap();
+
+        for file in &[&first, &other] {
+            let replaced = db
+                .add_new_file(&class.id, file, MarkerPolicy::Reject)
+                .await
+                .unwrap();
+            assert!(replaced.is_empty());
+        }
+
+        // 断られたときは並びも変わらない
+        let res = db
+            .add_new_file(&class.id, &second, MarkerPolicy::Reject)
+            .await;
+        assert_eq!(res, Err(DatabaseError::MarkerConflict));
+        assert_eq!(
+            db.get_class_by_id(&class.id).await.unwrap().files,
+            vec![first.clone(), other.clone()]
+        );
+
+        let replaced = db
+            .add_new_file(&class.id, &second, MarkerPolicy::Replace)
+            .await
+            .unwrap();
+        assert_eq!(replaced, vec![first]);
+        assert_eq!(
+            db.get_class_by_id(&class.id).await.unwrap().files,
+            vec![other, second]
+        );
+    }
 }
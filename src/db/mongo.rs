@@ -1,17 +1,91 @@
-use crate::db::{Database, DatabaseError, SimpleClassInfo};
+use crate::db::{
+    ClassQuery, ClassSortKey, Database, DatabaseError, FileQuery, FileUpdate, MarkerPolicy,
+    SimpleClassInfo, SortOrder,
+};
 use crate::model::*;
 use async_trait::async_trait;
-use mongodb::bson::{self, doc, Document};
-use mongodb::error::Error as MongoDBError;
-use mongodb::options::ClientOptions;
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::error::{Error as MongoDBError, ErrorKind, WriteFailure};
+use mongodb::options::{ClientOptions, FindOneAndUpdateOptions, FindOneOptions, ReturnDocument};
 use mongodb::{Client, Collection};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::time::Duration;
+use thiserror::Error;
 use tokio::stream::StreamExt;
+use uuid::Uuid;
+
+// リクエストは並行して来るので、コネクションをある程度まで張れるようにしておく
+const DEFAULT_MAX_POOL_SIZE: u32 = 16;
+
+// ユニークインデックスに反したときのエラーコード
+const DUPLICATE_KEY: i32 = 11000;
+
+// listIndexes でコレクションが無かったときのエラーコード
+const NAMESPACE_NOT_FOUND: i32 = 26;
 
 pub struct MongoDB {
     inner: Collection,
+    teachers: Collection,
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum MongoDBSetupError {
+    #[error("mongodb error: {0}")]
+    MongoDB(#[from] MongoDBError),
+
+    #[error("schema does not match: {}", .0.join(", "))]
+    SchemaMismatch(Vec<String>),
+}
+
+// 起動時に作り、定義が違っていれば起動を止めるインデックス (コレクション名, インデックス)
+// 同じ定義のインデックスが既にあれば作るときに何もしない
+fn indexes() -> Vec<(&'static str, Vec<Document>)> {
+    vec![
+        (
+            "classes",
+            vec![
+                // ARのクライアントはクラスの中でマーカーIDからファイルを探すので、クラスのIDと組にして引けるようにしておく
+                doc! { "key": { "id": 1, "files.markerID": 1 }, "name": "id_files_markerID" },
+                // IDと合言葉は、同時に作られても被らないようにユニークにする
+                doc! { "key": { "id": 1 }, "name": "id", "unique": true },
+                doc! { "key": { "passPhrase": 1 }, "name": "passPhrase", "unique": true },
+                // 先生は自分のクラスの一覧を見る
+                doc! { "key": { "owner": 1 }, "name": "owner" },
+                // ファイルの無いクラスが files.id を null として被らないように、ファイルのあるものだけに張る
+                doc! {
+                    "key": { "files.id": 1 },
+                    "name": "files_id",
+                    "unique": true,
+                    "partialFilterExpression": { "files.id": { "$exists": true } },
+                },
+            ],
+        ),
+        (
+            "teachers",
+            vec![
+                // 同じ名前で同時に登録されても、どちらかだけが通るようにする
+                doc! { "key": { "id": 1 }, "name": "id", "unique": true },
+                doc! { "key": { "name": 1 }, "name": "name", "unique": true },
+            ],
+        ),
+    ]
+}
+
+// $regex で文字列そのものに一致させるために、正規表現の記号をエスケープする
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}-".contains(c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
 }
 
 // (Log Error)
@@ -25,19 +99,356 @@ where
     }
 }
 
+// ユニークインデックスに反して書き込めなかったか
+fn is_duplicate_key(error: &MongoDBError) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::CommandError(e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+// 書き込みのエラーのうち、IDや合言葉が被ったものは Conflict にする
+fn write_error(error: MongoDBError) -> DatabaseError {
+    if is_duplicate_key(&error) {
+        log::warn!("MongoDB duplicate key: {:?}", error);
+        DatabaseError::Conflict
+    } else {
+        le(DatabaseError::ConnectionError)(error)
+    }
+}
+
+// サーバ側の時刻を持つ前に作られたクラスに updatedAt などを足す
+// それまでファイルの createdAt はクライアントが送ってきた値だったので clientCreatedAt にも残す
+async fn migrate_timestamps(classes: &Collection) -> Result<(), MongoDBError> {
+    let pipeline = vec![doc! {
+        "$set": {
+            "createdAt": { "$ifNull": ["$createdAt", 0_i64] },
+            "updatedAt": { "$ifNull": ["$createdAt", 0_i64] },
+            "files": {
+                "$map": {
+                    "input": "$files",
+                    "in": {
+                        "$mergeObjects": [
+                            "$$this",
+                            {
+                                "resourceInfo": {
+                                    "$mergeObjects": [
+                                        "$$this.resourceInfo",
+                                        {
+                                            "updatedAt": "$$this.resourceInfo.createdAt",
+                                            "clientCreatedAt": "$$this.resourceInfo.createdAt",
+                                        }
+                                    ]
+                                }
+                            }
+                        ]
+                    }
+                }
+            }
+        }
+    }];
+
+    let result = classes
+        .update_many(doc! { "updatedAt": { "$exists": false } }, pipeline, None)
+        .await?;
+
+    if result.modified_count > 0 {
+        log::info!("added timestamps to {} classes", result.modified_count);
+    }
+
+    Ok(())
+}
+
+// 先生の登録ができる前に作られたクラスには持ち主がいない
+// どの先生にも割り当てられない nil のIDを持ち主にして、合言葉で読めるが誰も変更できないクラスにする
+async fn migrate_owners(classes: &Collection) -> Result<(), MongoDBError> {
+    let result = classes
+        .update_many(
+            doc! { "owner": { "$exists": false } },
+            doc! { "$set": { "owner": Uuid::nil().to_string() } },
+            None,
+        )
+        .await?;
+
+    if result.modified_count > 0 {
+        log::info!("set nil owner to {} classes", result.modified_count);
+    }
+
+    Ok(())
+}
+
+async fn connect(url: &str) -> Result<mongodb::Database, MongoDBError> {
+    let mut client_options = ClientOptions::parse(url).await?;
+
+    client_options.app_name = Some("Blackboard".into());
+    client_options.min_pool_size = Some(0);
+    // MONGO_URL に maxPoolSize が書かれていればそちらを使う
+    client_options
+        .max_pool_size
+        .get_or_insert(DEFAULT_MAX_POOL_SIZE);
+    client_options.max_idle_time = Some(Duration::from_secs(15));
+
+    Ok(Client::with_options(client_options)?.database("blackboard"))
+}
+
+async fn create_indexes(database: &mongodb::Database) -> Result<(), MongoDBSetupError> {
+    for (collection, indexes) in indexes() {
+        let result = database
+            .run_command(
+                doc! { "createIndexes": collection, "indexes": indexes },
+                None,
+            )
+            .await;
+
+        // 同じ名前で定義の違うインデックスがあったり、ユニークにできないデータがあると作れない
+        // 何が違うのかを報告できるように、中身を調べてから止める
+        if let Err(e) = result {
+            log::error!("failed to create indexes on {}: {}", collection, e);
+
+            let mut problems = index_problems(database).await?;
+            problems.extend(duplicate_problems(database).await?);
+
+            return Err(if problems.is_empty() {
+                e.into()
+            } else {
+                MongoDBSetupError::SchemaMismatch(problems)
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// インデックスの定義で比べる項目 (キー, ユニークか, 部分インデックスの条件)
+// キーの 1 は作り方によって整数だったり小数だったりするので、数値は小数に揃えて比べる
+fn index_definition(index: &Document) -> (Vec<(String, Bson)>, bool, Option<Document>) {
+    let key = index
+        .get_document("key")
+        .map(|key| {
+            key.iter()
+                .map(|(field, order)| {
+                    let order = match order {
+                        Bson::Int32(n) => Bson::Double(f64::from(*n)),
+                        Bson::Int64(n) => Bson::Double(*n as f64),
+                        order => order.clone(),
+                    };
+
+                    (field.clone(), order)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let unique = index.get_bool("unique").unwrap_or(false);
+    let partial = index.get_document("partialFilterExpression").ok().cloned();
+
+    (key, unique, partial)
+}
+
+async fn index_problems(database: &mongodb::Database) -> Result<Vec<String>, MongoDBError> {
+    let mut problems = vec![];
+
+    for (collection, expected) in indexes() {
+        let response = database
+            .run_command(doc! { "listIndexes": collection }, None)
+            .await;
+
+        // コレクションがまだ無ければインデックスも無い
+        let existing = match response {
+            Ok(response) => response
+                .get_document("cursor")
+                .and_then(|cursor| cursor.get_array("firstBatch"))
+                .map(|batch| {
+                    batch
+                        .iter()
+                        .filter_map(|index| index.as_document().cloned())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+
+            Err(e) if matches!(e.kind.as_ref(), ErrorKind::CommandError(c) if c.code == NAMESPACE_NOT_FOUND) =>
+            {
+                vec![]
+            }
+
+            Err(e) => return Err(e),
+        };
+
+        for index in expected {
+            let name = index.get_str("name").unwrap_or_default();
+
+            match existing
+                .iter()
+                .find(|e| e.get_str("name").ok() == Some(name))
+            {
+                None => problems.push(format!("{}: index {} is missing", collection, name)),
+
+                Some(found) if index_definition(found) != index_definition(&index) => problems
+                    .push(format!(
+                        "{}: index {} is {}, expected {}",
+                        collection, name, found, index
+                    )),
+
+                Some(_) => {}
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+// indexes() でユニークにしている項目 (コレクション名, 項目)
+// ユニークインデックスはどれも項目が1つ
+fn unique_fields() -> Vec<(&'static str, String)> {
+    indexes()
+        .into_iter()
+        .flat_map(|(collection, indexes)| {
+            indexes
+                .into_iter()
+                .filter(|index| index.get_bool("unique").unwrap_or(false))
+                .filter_map(|index| index.get_document("key").ok()?.keys().next().cloned())
+                .map(move |field| (collection, field))
+        })
+        .collect()
+}
+
+// ユニークインデックスを張れない、値の被ったドキュメントを探す
+async fn duplicate_problems(database: &mongodb::Database) -> Result<Vec<String>, MongoDBError> {
+    let mut problems = vec![];
+
+    for (collection, field) in unique_fields() {
+        let mut pipeline = vec![];
+
+        // 配列の中の項目は要素ごとに展開してから数える
+        if field == "files.id" {
+            pipeline.push(doc! { "$unwind": "$files" });
+        }
+
+        pipeline.push(doc! { "$group": { "_id": format!("${}", field), "count": { "$sum": 1 } } });
+        pipeline.push(doc! { "$match": { "_id": { "$ne": null }, "count": { "$gt": 1 } } });
+
+        let mut duplicates = database
+            .collection(collection)
+            .aggregate(pipeline, None)
+            .await?;
+
+        while let Some(duplicate) = duplicates.next().await {
+            let duplicate = duplicate?;
+
+            problems.push(format!(
+                "{}: {} {} is used {} times",
+                collection,
+                field,
+                duplicate
+                    .get("_id")
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+                duplicate
+                    .get("count")
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+// 読み込めない形のドキュメントを探す
+async fn document_problems<T: DeserializeOwned>(
+    collection: &Collection,
+) -> Result<Vec<String>, MongoDBError> {
+    let mut documents = collection.find(None, None).await?;
+    let mut problems = vec![];
+
+    while let Some(document) = documents.next().await {
+        let document = document?;
+
+        if let Err(e) = bson::from_document::<T>(document.clone()) {
+            let id = document
+                .get("id")
+                .or_else(|| document.get("_id"))
+                .map(ToString::to_string)
+                .unwrap_or_default();
+
+            problems.push(format!(
+                "{}: document {} is invalid: {}",
+                collection.name(),
+                id,
+                e
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
 impl MongoDB {
-    pub async fn new(url: &str) -> Result<MongoDB, MongoDBError> {
-        let mut client_options = ClientOptions::parse(url).await?;
+    // インデックスを作って、定義が期待どおりか確かめてから使い始める
+    pub async fn new(url: &str) -> Result<MongoDB, MongoDBSetupError> {
+        let database = connect(url).await?;
+
+        create_indexes(&database).await?;
+
+        let problems = index_problems(&database).await?;
+
+        if !problems.is_empty() {
+            for problem in &problems {
+                log::error!("{}", problem);
+            }
 
-        client_options.app_name = Some("Blackboard".into());
-        client_options.min_pool_size = Some(0);
-        client_options.max_pool_size = Some(1);
-        client_options.max_idle_time = Some(Duration::from_secs(15));
+            return Err(MongoDBSetupError::SchemaMismatch(problems));
+        }
 
-        let database = Client::with_options(client_options)?.database("blackboard");
         let entries = database.collection("classes");
+        let teachers = database.collection("teachers");
+
+        migrate_timestamps(&entries).await?;
+        migrate_owners(&entries).await?;
 
-        Ok(MongoDB { inner: entries })
+        Ok(MongoDB {
+            inner: entries,
+            teachers,
+        })
+    }
+
+    // 何も書き換えずに、インデックスとドキュメントの形の問題を全部挙げる
+    pub async fn check_schema(url: &str) -> Result<Vec<String>, MongoDBError> {
+        let database = connect(url).await?;
+
+        let mut problems = index_problems(&database).await?;
+        problems.extend(duplicate_problems(&database).await?);
+
+        // updatedAt が無いのは古い形のままのクラスで、サーバを起動すれば移行される
+        let outdated = database
+            .collection("classes")
+            .count_documents(doc! { "updatedAt": { "$exists": false } }, None)
+            .await?;
+
+        if outdated > 0 {
+            problems.push(format!(
+                "classes: {} documents have no timestamps yet (they are migrated on startup)",
+                outdated
+            ));
+        }
+
+        let ownerless = database
+            .collection("classes")
+            .count_documents(doc! { "owner": { "$exists": false } }, None)
+            .await?;
+
+        if ownerless > 0 {
+            problems.push(format!(
+                "classes: {} documents have no owner yet (they get the nil owner on startup)",
+                ownerless
+            ));
+        }
+
+        problems.extend(document_problems::<Class>(&database.collection("classes")).await?);
+        problems.extend(document_problems::<Teacher>(&database.collection("teachers")).await?);
+
+        Ok(problems)
     }
 
     async fn search_by_doc<T>(
@@ -75,20 +486,256 @@ impl MongoDB {
             .map(|e| e.map_err(le(DatabaseError::DeserializeFailed)))
             .transpose()
     }
+
+    // 付け替え先のマーカーが他のファイルに使われていれば MarkerConflict で断る
+    async fn update_file_fields(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        update: &FileUpdate,
+    ) -> Result<File, DatabaseError> {
+        #[derive(Deserialize)]
+        struct DBResponse {
+            files: Vec<File>,
+        }
+
+        let mut set = Document::new();
+
+        if let Some(marker_id) = &update.marker_id {
+            set.insert("files.$[target].markerID", marker_id.0.as_str());
+        }
+
+        if let Some(file_name) = &update.file_name {
+            set.insert("files.$[target].resourceInfo.fileName", file_name.as_str());
+        }
+
+        // $set に空のドキュメントは渡せない
+        if set.is_empty() {
+            return self.get_file_in_class(class_id, file_id).await;
+        }
+
+        let now = EpochTime::now().millis();
+        set.insert("files.$[target].resourceInfo.updatedAt", now);
+        set.insert("updatedAt", now);
+
+        let mut filter = doc! { "id": class_id.0.to_string(), "files.id": file_id.0.to_string() };
+
+        // 付け替え先のマーカーを他のファイルが使っていれば一致させない
+        if let Some(marker_id) = &update.marker_id {
+            filter.insert(
+                "files",
+                doc! {
+                    "$not": {
+                        "$elemMatch": {
+                            "markerID": marker_id.0.as_str(),
+                            "id": { "$ne": file_id.0.to_string() }
+                        }
+                    }
+                },
+            );
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .array_filters(vec![doc! { "target.id": file_id.0.to_string() }])
+            .projection(doc! { "files": { "$elemMatch": { "id": file_id.0.to_string() } } })
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let response = self
+            .inner
+            .find_one_and_update(filter, doc! { "$set": set }, options)
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?;
+
+        match response {
+            Some(doc) => bson::from_document::<DBResponse>(doc)
+                .map_err(le(DatabaseError::DeserializeFailed))?
+                .files
+                .pop()
+                .ok_or(DatabaseError::FileNotFound),
+
+            // クラスかファイルが無ければそのエラーを返し、どちらもあればマーカーが使われている
+            None => self
+                .get_file_in_class(class_id, file_id)
+                .await
+                .and(Err(DatabaseError::MarkerConflict)),
+        }
+    }
+
+    // 付け替え先のマーカーを使っているファイルを外すのと付け替えるのを一回の更新で行い、
+    // 更新前のドキュメントから外したファイルと変更後のファイルを求める
+    async fn replace_marker(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        marker_id: &ArMarkerID,
+        file_name: Option<&str>,
+    ) -> Result<(File, Vec<File>), DatabaseError> {
+        #[derive(Deserialize)]
+        struct DBResponse {
+            #[serde(default)]
+            files: Vec<File>,
+        }
+
+        let target = file_id.0.to_string();
+        let updated_at = EpochTime::now();
+
+        // "$" で始まる値がフィールドとして読まれないように $literal で包む
+        let marker = doc! { "$literal": marker_id.0.as_str() };
+        let mut resource_info = doc! { "updatedAt": updated_at.millis() };
+
+        if let Some(file_name) = file_name {
+            resource_info.insert("fileName", doc! { "$literal": file_name });
+        }
+
+        let pipeline = vec![doc! {
+            "$set": {
+                "updatedAt": updated_at.millis(),
+                "files": {
+                    "$map": {
+                        "input": {
+                            "$filter": {
+                                "input": "$files",
+                                "cond": {
+                                    "$or": [
+                                        { "$ne": ["$$this.markerID", marker.clone()] },
+                                        { "$eq": ["$$this.id", &target] }
+                                    ]
+                                }
+                            }
+                        },
+                        "in": {
+                            "$cond": [
+                                { "$eq": ["$$this.id", &target] },
+                                {
+                                    "$mergeObjects": [
+                                        "$$this",
+                                        {
+                                            "markerID": marker,
+                                            "resourceInfo": {
+                                                "$mergeObjects": ["$$this.resourceInfo", resource_info]
+                                            }
+                                        }
+                                    ]
+                                },
+                                "$$this"
+                            ]
+                        }
+                    }
+                }
+            }
+        }];
+
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "files": true })
+            .return_document(ReturnDocument::Before)
+            .build();
+
+        let response = self
+            .inner
+            .find_one_and_update(
+                doc! { "id": class_id.0.to_string(), "files.id": &target },
+                pipeline,
+                options,
+            )
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?;
+
+        let before = match response {
+            Some(doc) => {
+                bson::from_document::<DBResponse>(doc)
+                    .map_err(le(DatabaseError::DeserializeFailed))?
+                    .files
+            }
+
+            None if self.class_id_exists(class_id).await? => {
+                return Err(DatabaseError::FileNotFound)
+            }
+            None => return Err(DatabaseError::ClassNotFound),
+        };
+
+        let (mut files, replaced) = before
+            .into_iter()
+            .filter(|f| f.id == *file_id || f.marker_id == *marker_id)
+            .partition::<Vec<_>, _>(|f| f.id == *file_id);
+
+        let mut file = files.pop().ok_or(DatabaseError::FileNotFound)?;
+        file.marker_id = marker_id.clone();
+        file.resource_info.updated_at = updated_at;
+
+        if let Some(file_name) = file_name {
+            file.resource_info.filename = file_name.into();
+        }
+
+        Ok((file, replaced))
+    }
 }
 
 #[async_trait]
 impl Database for MongoDB {
-    async fn get_all_classes(&self) -> Result<Vec<SimpleClassInfo>, DatabaseError> {
+    async fn query_classes(
+        &self,
+        query: &ClassQuery,
+    ) -> Result<Vec<SimpleClassInfo>, DatabaseError> {
+        let sort_field = match query.sort {
+            ClassSortKey::Name => "name",
+            ClassSortKey::CreatedAt => "createdAt",
+        };
+
+        let (direction, after_op) = match query.order {
+            SortOrder::Ascending => (1, "$gt"),
+            SortOrder::Descending => (-1, "$lt"),
+        };
+
+        let mut conditions = vec![];
+
+        if let Some(owner) = &query.owner {
+            conditions.push(doc! { "owner": owner.0.to_string() });
+        }
+
+        if let Some(name) = &query.name_contains {
+            conditions.push(doc! {
+                "name": { "$regex": escape_regex(name), "$options": "i" }
+            });
+        }
+
+        // 並び替えのキーが前のページの最後より後ろか、同じでIDが後ろのもの
+        if let Some(after) = &query.after {
+            let after_key = match query.sort {
+                ClassSortKey::Name => Bson::from(after.name.as_str()),
+                ClassSortKey::CreatedAt => Bson::from(after.created_at.millis()),
+            };
+
+            let mut beyond_key = Document::new();
+            beyond_key.insert(sort_field, doc! { after_op: after_key.clone() });
+
+            let mut beyond_id = Document::new();
+            beyond_id.insert(sort_field, after_key);
+            beyond_id.insert("id", doc! { after_op: after.id.0.to_string() });
+
+            conditions.push(doc! { "$or": [beyond_key, beyond_id] });
+        }
+
+        let mut sort = Document::new();
+        sort.insert(sort_field, direction);
+        sort.insert("id", direction);
+
+        // $and に空の配列は渡せない
+        let filter = if conditions.is_empty() {
+            doc! {}
+        } else {
+            doc! { "$and": conditions }
+        };
+
+        let pipeline = vec![
+            doc! { "$match": filter },
+            doc! { "$sort": sort },
+            doc! { "$limit": query.limit as i64 },
+            doc! { "$project": { "files": false } },
+        ];
+
         self.inner
-            .aggregate(
-                vec![doc! {
-                    "$project": {
-                        "files": false
-                    }
-                }],
-                None,
-            )
+            .aggregate(pipeline, None)
             .await
             .map_err(le(DatabaseError::ConnectionError))?
             .map(|d| d.map(bson::from_document::<SimpleClassInfo>))
@@ -98,13 +745,13 @@ impl Database for MongoDB {
             .map_err(le(DatabaseError::ConnectionError))?
     }
 
-    async fn save_new_class(&mut self, class: &Class) -> Result<(), DatabaseError> {
+    async fn save_new_class(&self, class: &Class) -> Result<(), DatabaseError> {
         let doc = bson::to_document(class).map_err(le(DatabaseError::SerializeFailed))?;
 
         self.inner
             .insert_one(doc, None)
             .await
-            .map_err(le(DatabaseError::ConnectionError))?;
+            .map_err(write_error)?;
 
         Ok(())
     }
@@ -112,7 +759,7 @@ impl Database for MongoDB {
     async fn get_class_by_id(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
         self.search_by_doc(doc! { "id": class_id.0.to_string() })
             .await?
-            .ok_or_else(|| DatabaseError::ClassNotFound)
+            .ok_or(DatabaseError::ClassNotFound)
     }
 
     async fn get_class_by_pass_phrase(
@@ -121,38 +768,37 @@ impl Database for MongoDB {
     ) -> Result<Class, DatabaseError> {
         self.search_by_doc(doc! { "passPhrase": &pass_phrase.0 })
             .await?
-            .ok_or_else(|| DatabaseError::ClassNotFound)
+            .ok_or(DatabaseError::ClassNotFound)
     }
 
-    async fn rename_class(
-        &mut self,
-        class_id: &ClassID,
-        new_name: &str,
-    ) -> Result<(), DatabaseError> {
+    async fn rename_class(&self, class_id: &ClassID, new_name: &str) -> Result<(), DatabaseError> {
+        // 書き換えたかどうかだけ分かればいいので、中身は取ってこない
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "_id": true })
+            .build();
+
         self.inner
-            .update_one(
+            .find_one_and_update(
                 doc! { "id": class_id.0.to_string() },
-                doc! { "$set": { "name": new_name } },
-                None,
+                doc! { "$set": { "name": new_name, "updatedAt": EpochTime::now().millis() } },
+                options,
             )
             .await
-            .map_err(le(DatabaseError::ConnectionError))?;
+            .map_err(le(DatabaseError::ConnectionError))?
+            .ok_or(DatabaseError::ClassNotFound)?;
 
         Ok(())
     }
 
-    async fn delete_class(&mut self, class_id: &ClassID) -> Result<Class, DatabaseError> {
-        let class = self
-            .search_by_doc(doc! { "id": class_id.0.to_string() })
-            .await?
-            .ok_or_else(|| DatabaseError::ClassNotFound)?;
-
+    async fn delete_class(&self, class_id: &ClassID) -> Result<Class, DatabaseError> {
+        // 探してから消すと間に他のリクエストが消したときに両方成功してしまうので、一度に行う
         self.inner
-            .delete_one(doc! { "id": class_id.0.to_string() }, None)
+            .find_one_and_delete(doc! { "id": class_id.0.to_string() }, None)
             .await
-            .map_err(le(DatabaseError::ConnectionError))?;
-
-        Ok(class)
+            .map_err(le(DatabaseError::ConnectionError))?
+            .ok_or(DatabaseError::ClassNotFound)
+            .map(bson::from_document)?
+            .map_err(le(DatabaseError::DeserializeFailed))
     }
 
     async fn class_id_exists(&self, class_id: &ClassID) -> Result<bool, DatabaseError> {
@@ -175,184 +821,503 @@ impl Database for MongoDB {
         Ok(result.is_some())
     }
 
-    async fn get_files(&self, class_id: &ClassID) -> Result<Vec<File>, DatabaseError> {
+    async fn query_files(
+        &self,
+        class_id: &ClassID,
+        query: &FileQuery,
+    ) -> Result<Vec<File>, DatabaseError> {
+        #[derive(Deserialize)]
+        struct DBResponse {
+            files: Option<File>,
+        }
+
+        let (direction, after_op) = match query.order {
+            SortOrder::Ascending => (1, "$gt"),
+            SortOrder::Descending => (-1, "$lt"),
+        };
+
+        let created_at = "$$file.resourceInfo.createdAt";
+        let mut conditions = vec![];
+
+        // "$" で始まる値がフィールドとして読まれないように $literal で包む
+        if let Some(marker_id) = &query.marker_id {
+            let marker = doc! { "$literal": marker_id.0.as_str() };
+            conditions.push(doc! { "$eq": ["$$file.markerID", marker] });
+        }
+
+        if let Some(from) = query.created_from {
+            conditions.push(doc! { "$gte": [created_at, from.millis()] });
+        }
+
+        if let Some(to) = query.created_to {
+            conditions.push(doc! { "$lt": [created_at, to.millis()] });
+        }
+
+        // 作成時刻が前のページの最後より後ろか、同じでIDが後ろのもの
+        if let Some(after) = &query.after {
+            conditions.push(doc! {
+                "$or": [
+                    { after_op: [created_at, after.created_at.millis()] },
+                    {
+                        "$and": [
+                            { "$eq": [created_at, after.created_at.millis()] },
+                            { after_op: ["$$file.id", after.id.0.to_string()] },
+                        ]
+                    },
+                ]
+            });
+        }
+
+        // 配列のまま取ってこないように、サーバ側で絞り込んでから1件ずつに展開して並べる
+        // 条件に合うファイルが無くても、クラスがあることは分かるように空のまま1件残す
+        let pipeline = vec![
+            doc! { "$match": { "id": class_id.0.to_string() } },
+            doc! {
+                "$project": {
+                    "_id": false,
+                    "files": {
+                        "$filter": {
+                            "input": "$files",
+                            "as": "file",
+                            "cond": { "$and": conditions },
+                        }
+                    }
+                }
+            },
+            doc! { "$unwind": { "path": "$files", "preserveNullAndEmptyArrays": true } },
+            doc! {
+                "$sort": {
+                    "files.resourceInfo.createdAt": direction,
+                    "files.id": direction,
+                }
+            },
+            doc! { "$limit": query.limit as i64 },
+        ];
+
+        let responses = self
+            .inner
+            .aggregate(pipeline, None)
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?
+            .map(|d| d.map(bson::from_document::<DBResponse>))
+            .map(|d| d.map(|r| r.map_err(le(DatabaseError::DeserializeFailed))))
+            .collect::<Result<Result<Vec<_>, _>, _>>()
+            .await
+            .map_err(le(DatabaseError::ConnectionError))??;
+
+        if responses.is_empty() {
+            return Err(DatabaseError::ClassNotFound);
+        }
+
+        Ok(responses.into_iter().filter_map(|r| r.files).collect())
+    }
+
+    async fn get_files_by_marker(
+        &self,
+        class_id: &ClassID,
+        marker_id: &ArMarkerID,
+    ) -> Result<Vec<File>, DatabaseError> {
         #[derive(Deserialize)]
         struct DBResponse {
             files: Vec<File>,
         }
 
+        // "$" で始まる値がフィールドとして読まれないように $literal で包む
+        let marker = doc! { "$literal": marker_id.0.as_str() };
+
         let response = self
             .aggregate_one_and_parse::<DBResponse>(vec![
                 doc! {
                     "$match": {
-                        "id": class_id.0.to_string()
+                        "id": class_id.0.to_string(),
+                        "files.markerID": &marker_id.0,
                     }
                 },
                 doc! {
                     "$project": {
-                        "files": true
+                        "files": {
+                            "$filter": {
+                                "input": "$files",
+                                "as": "file",
+                                "cond": { "$eq": ["$$file.markerID", marker] },
+                            }
+                        }
                     }
                 },
             ])
-            .await?
-            .ok_or_else(|| DatabaseError::ClassNotFound)?;
+            .await?;
 
-        Ok(response.files)
+        match response {
+            Some(response) => Ok(response.files),
+            None if self.class_id_exists(class_id).await? => Ok(vec![]),
+            None => Err(DatabaseError::ClassNotFound),
+        }
     }
 
-    async fn add_new_file(&mut self, class_id: &ClassID, file: &File) -> Result<(), DatabaseError> {
+    async fn add_new_file(
+        &self,
+        class_id: &ClassID,
+        file: &File,
+        policy: MarkerPolicy,
+    ) -> Result<Vec<File>, DatabaseError> {
         let file_doc = bson::to_document(file).map_err(le(DatabaseError::SerializeFailed))?;
+        let marker_id = file.marker_id.0.as_str();
+        let file_id = file.id.0.to_string();
+
+        // ユニークインデックスは同じドキュメントの中での重複までは見ないので、
+        // 同じクラスに同じIDのファイルが無いことは filter で確かめる
+        match policy {
+            MarkerPolicy::Reject => {
+                // 同じマーカーのファイルが無いときだけ追加されるように、条件を filter に入れる
+                let update_result = self
+                    .inner
+                    .update_one(
+                        doc! {
+                            "id": class_id.0.to_string(),
+                            "files.markerID": { "$ne": marker_id },
+                            "files.id": { "$ne": &file_id },
+                        },
+                        doc! {
+                            "$push": { "files": file_doc },
+                            "$set": { "updatedAt": EpochTime::now().millis() },
+                        },
+                        None,
+                    )
+                    .await
+                    .map_err(write_error)?;
+
+                if update_result.matched_count == 1 {
+                    Ok(vec![])
+                } else if !self.class_id_exists(class_id).await? {
+                    Err(DatabaseError::ClassNotFound)
+                } else if self.file_id_exists(&file.id).await? {
+                    Err(DatabaseError::Conflict)
+                } else {
+                    Err(DatabaseError::MarkerConflict)
+                }
+            }
 
-        let update_result = self
-            .inner
-            .update_one(
-                doc! { "id": class_id.0.to_string() },
-                doc! { "$push": { "files": file_doc }},
-                None,
-            )
-            .await
-            .map_err(le(DatabaseError::ConnectionError))?;
+            MarkerPolicy::Replace => {
+                #[derive(Deserialize)]
+                struct DBResponse {
+                    #[serde(default)]
+                    files: Vec<File>,
+                }
 
-        if update_result.matched_count == 1 && update_result.modified_count == 1 {
-            Ok(())
-        } else {
-            log::error!(
-                "couldn't update: match: {}, mod: {}",
-                update_result.matched_count,
-                update_result.modified_count
-            );
-            Err(DatabaseError::ClassNotFound)
+                // 外すのと追加するのを一回の更新で行い、更新前のドキュメントから外したファイルを取り出す
+                let options = FindOneAndUpdateOptions::builder()
+                    .projection(doc! { "files": true })
+                    .build();
+
+                // "$" で始まる値がフィールドとして読まれないように $literal で包む
+                let marker = doc! { "$literal": marker_id };
+
+                let pipeline = vec![doc! {
+                    "$set": {
+                        "updatedAt": EpochTime::now().millis(),
+                        "files": {
+                            "$concatArrays": [
+                                {
+                                    "$filter": {
+                                        "input": "$files",
+                                        "cond": { "$ne": ["$$this.markerID", marker] }
+                                    }
+                                },
+                                { "$literal": [file_doc] }
+                            ]
+                        }
+                    }
+                }];
+
+                let response = self
+                    .inner
+                    .find_one_and_update(
+                        doc! { "id": class_id.0.to_string(), "files.id": { "$ne": &file_id } },
+                        pipeline,
+                        options,
+                    )
+                    .await
+                    .map_err(write_error)?;
+
+                match response {
+                    Some(doc) => Ok(bson::from_document::<DBResponse>(doc)
+                        .map_err(le(DatabaseError::DeserializeFailed))?
+                        .files
+                        .into_iter()
+                        .filter(|f| f.marker_id == file.marker_id)
+                        .collect()),
+
+                    None if self.class_id_exists(class_id).await? => Err(DatabaseError::Conflict),
+                    None => Err(DatabaseError::ClassNotFound),
+                }
+            }
         }
     }
 
-    async fn get_file_by_id(&self, file_id: &FileID) -> Result<File, DatabaseError> {
+    async fn get_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
         #[derive(Deserialize)]
         struct DBResponse {
-            files: File,
+            #[serde(default)]
+            files: Vec<File>,
         }
 
-        self.aggregate_one_and_parse::<DBResponse>(vec![
-            doc! {
-                "$project": {
-                    "files": true
-                }
-            },
-            doc! {
-                "$unwind": {
-                    "path": "$files"
-                }
-            },
-            doc! {
-                "$match": {
-                    "files.id": file_id.0.to_string()
-                }
-            },
-        ])
-        .await?
-        .map(|e| e.files)
-        .ok_or_else(|| DatabaseError::FileNotFound)
+        let options = FindOneOptions::builder()
+            .projection(doc! { "files": { "$elemMatch": { "id": file_id.0.to_string() } } })
+            .build();
+
+        self.inner
+            .find_one(doc! { "id": class_id.0.to_string() }, options)
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?
+            .ok_or(DatabaseError::ClassNotFound)
+            .map(bson::from_document::<DBResponse>)?
+            .map_err(le(DatabaseError::DeserializeFailed))?
+            .files
+            .pop()
+            .ok_or(DatabaseError::FileNotFound)
     }
 
-    async fn delete_file(&mut self, file_id: &FileID) -> Result<File, DatabaseError> {
-        let file = self.get_file_by_id(file_id).await?;
+    async fn delete_file_in_class(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+    ) -> Result<File, DatabaseError> {
+        #[derive(Deserialize)]
+        struct DBResponse {
+            files: Vec<File>,
+        }
+
+        // 消す前のドキュメントから消したファイルだけ取り出す
+        let options = FindOneAndUpdateOptions::builder()
+            .projection(doc! { "files": { "$elemMatch": { "id": file_id.0.to_string() } } })
+            .build();
 
-        let result = self
+        let response = self
             .inner
-            .update_many(
-                doc! {},
-                doc! { "$pull": { "files": { "id": file_id.0.to_string() } } },
-                None,
+            .find_one_and_update(
+                doc! { "id": class_id.0.to_string(), "files.id": file_id.0.to_string() },
+                doc! {
+                    "$pull": { "files": { "id": file_id.0.to_string() } },
+                    "$set": { "updatedAt": EpochTime::now().millis() },
+                },
+                options,
             )
             .await
             .map_err(le(DatabaseError::ConnectionError))?;
 
-        assert!(result.modified_count == 1);
+        match response {
+            Some(doc) => bson::from_document::<DBResponse>(doc)
+                .map_err(le(DatabaseError::DeserializeFailed))?
+                .files
+                .pop()
+                .ok_or(DatabaseError::FileNotFound),
+
+            None if self.class_id_exists(class_id).await? => Err(DatabaseError::FileNotFound),
+            None => Err(DatabaseError::ClassNotFound),
+        }
+    }
 
-        Ok(file)
+    async fn update_file(
+        &self,
+        class_id: &ClassID,
+        file_id: &FileID,
+        update: &FileUpdate,
+        policy: MarkerPolicy,
+    ) -> Result<(File, Vec<File>), DatabaseError> {
+        match (&update.marker_id, policy) {
+            (Some(marker_id), MarkerPolicy::Replace) => {
+                self.replace_marker(class_id, file_id, marker_id, update.file_name.as_deref())
+                    .await
+            }
+
+            _ => self
+                .update_file_fields(class_id, file_id, update)
+                .await
+                .map(|file| (file, vec![])),
+        }
     }
 
     async fn file_id_exists(&self, file_id: &FileID) -> Result<bool, DatabaseError> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": true })
+            .build();
+
         let result = self
             .inner
-            .aggregate(
-                vec![
-                    doc! {
-                        "$project": {
-                            "files": true
-                        }
-                    },
-                    doc! {
-                        "$unwind": {
-                            "path": "$files"
-                        }
-                    },
-                    doc! {
-                        "$match": {
-                            "files.id": file_id.0.to_string()
-                        }
-                    },
-                ],
-                None,
-            )
+            .find_one(doc! { "files.id": file_id.0.to_string() }, options)
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?;
+
+        Ok(result.is_some())
+    }
+
+    async fn save_new_teacher(&self, teacher: &Teacher) -> Result<(), DatabaseError> {
+        let doc = bson::to_document(teacher).map_err(le(DatabaseError::SerializeFailed))?;
+
+        self.teachers
+            .insert_one(doc, None)
+            .await
+            .map_err(write_error)?;
+
+        Ok(())
+    }
+
+    async fn get_teacher_by_name(&self, name: &str) -> Result<Teacher, DatabaseError> {
+        self.teachers
+            .find_one(doc! { "name": name }, None)
             .await
             .map_err(le(DatabaseError::ConnectionError))?
-            .next()
+            .map(bson::from_document)
+            .map(|e| e.map_err(le(DatabaseError::DeserializeFailed)))
+            .transpose()?
+            .ok_or(DatabaseError::TeacherNotFound)
+    }
+
+    async fn teacher_id_exists(&self, teacher_id: &TeacherID) -> Result<bool, DatabaseError> {
+        let result = self
+            .teachers
+            .find_one(doc! { "id": teacher_id.0.to_string() }, None)
+            .await
+            .map_err(le(DatabaseError::ConnectionError))?;
+
+        Ok(result.is_some())
+    }
+
+    async fn teacher_name_exists(&self, name: &str) -> Result<bool, DatabaseError> {
+        let result = self
+            .teachers
+            .find_one(doc! { "name": name }, None)
             .await
-            .is_some();
+            .map_err(le(DatabaseError::ConnectionError))?;
 
-        Ok(result)
+        Ok(result.is_some())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::sync::Arc;
     use tokio::runtime::Builder;
-    use tokio::sync::Mutex;
 
-    // requires mongodb on localhost.
+    // MongoDBが要るテストは #[ignore] にしてあるので、
+    // MONGODB_TEST_URL (例: mongodb://localhost) を指定して cargo test -- --ignored で動かす
+    fn test_url() -> String {
+        std::env::var("MONGODB_TEST_URL").expect("MONGODB_TEST_URL is not set")
+    }
+
+    #[test]
+    fn compares_index_definitions() {
+        let expected = doc! { "key": { "id": 1 }, "name": "id", "unique": true };
+
+        // サーバから返ってくるものは余計な項目が付いていたり、数値の型が違ったりする
+        let found = doc! { "v": 2, "key": { "id": 1.0 }, "name": "id", "unique": true };
+        assert_eq!(index_definition(&found), index_definition(&expected));
+
+        let not_unique = doc! { "v": 2, "key": { "id": 1 }, "name": "id" };
+        assert_ne!(index_definition(&not_unique), index_definition(&expected));
+
+        let descending = doc! { "v": 2, "key": { "id": -1 }, "name": "id", "unique": true };
+        assert_ne!(index_definition(&descending), index_definition(&expected));
+    }
+
+    #[test]
+    fn checks_duplicates_of_unique_fields() {
+        let fields = unique_fields();
+
+        assert!(fields.contains(&("classes", "passPhrase".into())));
+        assert!(fields.contains(&("classes", "files.id".into())));
+        assert!(fields.contains(&("teachers", "name".into())));
+        assert!(!fields.contains(&("classes", "owner".into())));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn conforms_to_database() {
+        let url = test_url();
+
+        let db = MongoDB::new(&url)
+            .await
+            .expect("failed to create mongodb handle");
+
+        crate::db::conformance::check(&db).await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn migrates_ownerless_classes() {
+        let url = test_url();
+
+        let db = MongoDB::new(&url)
+            .await
+            .expect("failed to create mongodb handle");
+
+        let owner = TeacherID::new(&db).await.unwrap();
+        let class = Class::new(&db, "理科".into(), owner).await.unwrap();
+
+        // 先生の登録ができる前の形で入れる
+        let mut document = bson::to_document(&class).unwrap();
+        document.remove("owner");
+        db.inner.insert_one(document, None).await.unwrap();
+
+        migrate_owners(&db.inner).await.unwrap();
+
+        let got = db.get_class_by_id(&class.id).await.unwrap();
+        assert_eq!(got.owner, TeacherID(Uuid::nil()));
+
+        db.delete_class(&class.id).await.unwrap();
+    }
+
     #[test]
+    #[ignore]
     fn mongo_test() {
+        let url = test_url();
+
         env_logger::init();
-        async fn test() {
-            let db = MongoDB::new("mongodb://localhost")
+        async fn test(url: String) {
+            let db = MongoDB::new(&url)
                 .await
                 .expect("failed to create mongodb handle");
 
-            let db = Arc::new(Mutex::new(db));
+            let owner = TeacherID::new(&db)
+                .await
+                .expect("failed to generate teacher id");
 
             let mut classes = vec![
-                Class::new(&db, "理科".into())
+                Class::new(&db, "理科".into(), owner.clone())
                     .await
                     .expect("failed to create class"),
-                Class::new(&db, "社会".into())
+                Class::new(&db, "社会".into(), owner.clone())
                     .await
                     .expect("failed to create class"),
             ];
 
-            // save_new_class, get_all_classes
+            // save_new_class, query_classes
             {
                 for class in &classes {
-                    db.lock()
-                        .await
-                        .save_new_class(&class)
+                    db.save_new_class(class)
                         .await
                         .expect("failed to save class");
                 }
 
+                let query = ClassQuery {
+                    owner: Some(owner.clone()),
+                    name_contains: None,
+                    sort: ClassSortKey::CreatedAt,
+                    order: SortOrder::Ascending,
+                    after: None,
+                    limit: 100,
+                };
+
                 let got_response = db
-                    .lock()
-                    .await
-                    .get_all_classes()
+                    .query_classes(&query)
                     .await
                     .expect("failed to retrieve classes");
 
-                let models = classes.iter().map(|class| SimpleClassInfo {
-                    name: class.name.clone(),
-                    id: class.id.clone(),
-                    pass_phrase: class.pass_phrase.clone(),
-                });
+                let models = classes.iter().map(SimpleClassInfo::from);
 
                 for model in models {
                     assert!(got_response.contains(&model));
@@ -362,8 +1327,6 @@ mod test {
             // get_class_by_id
             {
                 let got_response = db
-                    .lock()
-                    .await
                     .get_class_by_id(&classes[0].id)
                     .await
                     .expect("failed to retrieve class");
@@ -374,8 +1337,6 @@ mod test {
             // get_class_by_passphrase
             {
                 let got_response = db
-                    .lock()
-                    .await
                     .get_class_by_pass_phrase(&classes[1].pass_phrase)
                     .await
                     .expect("failed to retrieve class");
@@ -386,8 +1347,6 @@ mod test {
             // class_id_exists
             {
                 let res = db
-                    .lock()
-                    .await
                     .class_id_exists(&classes[1].id)
                     .await
                     .expect("failed to check whether class id exists");
@@ -399,9 +1358,7 @@ mod test {
                         .await
                         .expect("failed to generate class id");
 
-                    db.lock()
-                        .await
-                        .class_id_exists(&not_exist_id)
+                    db.class_id_exists(&not_exist_id)
                         .await
                         .expect("failed to check whether class id exists")
                 };
@@ -412,8 +1369,6 @@ mod test {
             // pass_phrase_exists
             {
                 let res = db
-                    .lock()
-                    .await
                     .pass_phrase_exists(&classes[0].pass_phrase)
                     .await
                     .expect("failed to check whether pass phrase exists");
@@ -424,9 +1379,7 @@ mod test {
                         .await
                         .expect("failed to generate class id");
 
-                    db.lock()
-                        .await
-                        .pass_phrase_exists(&not_exist_pass)
+                    db.pass_phrase_exists(&not_exist_pass)
                         .await
                         .expect("failed to check whether class id exists")
                 };
@@ -435,58 +1388,60 @@ mod test {
 
             // rename_class
             {
-                db.lock()
-                    .await
-                    .rename_class(&classes[1].id, "英語")
+                db.rename_class(&classes[1].id, "英語")
                     .await
                     .expect("failed to rename class");
 
                 classes[1].name = "英語".into();
 
                 let after0 = db
-                    .lock()
-                    .await
                     .get_class_by_id(&classes[0].id)
                     .await
                     .expect("failed to get class");
 
                 let after1 = db
-                    .lock()
-                    .await
                     .get_class_by_id(&classes[1].id)
                     .await
                     .expect("failed to get class");
 
+                assert!(after1.updated_at >= classes[1].updated_at);
+                classes[1].updated_at = after1.updated_at;
+
                 assert_eq!(classes[0], after0);
                 assert_eq!(classes[1], after1);
+
+                let not_exist_id = ClassID::new(&db)
+                    .await
+                    .expect("failed to generate class id");
+
+                let res = db.rename_class(&not_exist_id, "英語").await;
+                assert_eq!(res, Err(DatabaseError::ClassNotFound));
             }
 
+            let other_class_id = classes[1].id.clone();
             let file_test_class = &mut classes[0];
             let mut files = vec![
-                File::new(
-                    &db,
-                    ArMarkerID("foo_marker".into()),
-                    "foo.png".into(),
-                    chrono::Utc::now(),
-                )
-                .await
-                .expect("failed to create new file"),
-                File::new(
-                    &db,
-                    ArMarkerID("bar_marker".into()),
-                    "bar.png".into(),
-                    chrono::Utc::now(),
-                )
-                .await
-                .expect("failed to create new file"),
+                File::new(&db, ArMarkerID("foo_marker".into()), "foo.png".into(), None)
+                    .await
+                    .expect("failed to create new file"),
+                File::new(&db, ArMarkerID("bar_marker".into()), "bar.png".into(), None)
+                    .await
+                    .expect("failed to create new file"),
             ];
 
-            // add_new_file, get_files
+            let all_files = FileQuery {
+                marker_id: None,
+                created_from: None,
+                created_to: None,
+                order: SortOrder::Ascending,
+                after: None,
+                limit: 100,
+            };
+
+            // add_new_file, query_files
             {
                 for file in &files {
-                    db.lock()
-                        .await
-                        .add_new_file(&file_test_class.id, file)
+                    db.add_new_file(&file_test_class.id, file, MarkerPolicy::Reject)
                         .await
                         .expect("failed to add new file");
 
@@ -494,16 +1449,17 @@ mod test {
                 }
 
                 let res = db
-                    .lock()
-                    .await
+                    .add_new_file(&file_test_class.id, &files[0], MarkerPolicy::Reject)
+                    .await;
+                assert_eq!(res, Err(DatabaseError::MarkerConflict));
+
+                let res = db
                     .get_class_by_id(&file_test_class.id)
                     .await
                     .expect("failed to get class");
 
                 let res_files = db
-                    .lock()
-                    .await
-                    .get_files(&file_test_class.id)
+                    .query_files(&file_test_class.id, &all_files)
                     .await
                     .expect("failed to get files");
 
@@ -516,11 +1472,26 @@ mod test {
                 }
             }
 
-            // file_id_exists
+            // get_files_by_marker
             {
                 let res = db
-                    .lock()
+                    .get_files_by_marker(&file_test_class.id, &files[0].marker_id)
                     .await
+                    .expect("failed to get files by marker");
+
+                assert_eq!(res, vec![files[0].clone()]);
+
+                let res = db
+                    .get_files_by_marker(&other_class_id, &files[0].marker_id)
+                    .await
+                    .expect("failed to get files by marker");
+
+                assert!(res.is_empty());
+            }
+
+            // file_id_exists
+            {
+                let res = db
                     .file_id_exists(&files[0].id)
                     .await
                     .expect("failed to check whether file id exists");
@@ -532,9 +1503,7 @@ mod test {
                         .await
                         .expect("failed to create new file id");
 
-                    db.lock()
-                        .await
-                        .file_id_exists(&not_exist_id)
+                    db.file_id_exists(&not_exist_id)
                         .await
                         .expect("failed to check whether file id exists")
                 };
@@ -542,34 +1511,89 @@ mod test {
                 assert!(!res);
             }
 
-            // get_file_by_id
+            // get_file_in_class
             {
                 let res = db
-                    .lock()
-                    .await
-                    .get_file_by_id(&files[1].id)
+                    .get_file_in_class(&file_test_class.id, &files[1].id)
                     .await
                     .expect("failed to get file");
 
                 assert_eq!(res, files[1]);
+
+                let res = db.get_file_in_class(&other_class_id, &files[1].id).await;
+
+                assert_eq!(res, Err(DatabaseError::FileNotFound));
+            }
+
+            // update_file
+            {
+                let update = FileUpdate {
+                    marker_id: Some(files[0].marker_id.clone()),
+                    file_name: None,
+                };
+
+                let res = db
+                    .update_file(
+                        &file_test_class.id,
+                        &files[1].id,
+                        &update,
+                        MarkerPolicy::Reject,
+                    )
+                    .await;
+                assert_eq!(res, Err(DatabaseError::MarkerConflict));
+
+                let update = FileUpdate {
+                    marker_id: Some(ArMarkerID("baz_marker".into())),
+                    file_name: Some("baz.png".into()),
+                };
+
+                let (updated, _) = db
+                    .update_file(
+                        &file_test_class.id,
+                        &files[1].id,
+                        &update,
+                        MarkerPolicy::Reject,
+                    )
+                    .await
+                    .expect("failed to update file");
+
+                assert!(updated.resource_info.updated_at >= files[1].resource_info.updated_at);
+
+                files[1].marker_id = ArMarkerID("baz_marker".into());
+                files[1].resource_info.filename = "baz.png".into();
+                files[1].resource_info.updated_at = updated.resource_info.updated_at;
+                assert_eq!(updated, files[1]);
+
+                let res = db
+                    .get_file_in_class(&file_test_class.id, &files[1].id)
+                    .await
+                    .expect("failed to get file");
+                assert_eq!(res, files[1]);
+
+                let res = db
+                    .update_file(&other_class_id, &files[1].id, &update, MarkerPolicy::Reject)
+                    .await;
+                assert_eq!(res, Err(DatabaseError::FileNotFound));
             }
 
-            // delete_file
+            // delete_file_in_class
             {
                 let deleted = db
-                    .lock()
-                    .await
-                    .delete_file(&files[0].id)
+                    .delete_file_in_class(&file_test_class.id, &files[0].id)
                     .await
                     .expect("failed to delete file");
 
                 assert_eq!(files[0], deleted);
+
+                let res = db
+                    .delete_file_in_class(&file_test_class.id, &files[0].id)
+                    .await;
+                assert_eq!(res, Err(DatabaseError::FileNotFound));
+
                 files.remove(0);
 
                 let res_files = db
-                    .lock()
-                    .await
-                    .get_files(&file_test_class.id)
+                    .query_files(&file_test_class.id, &all_files)
                     .await
                     .expect("failed to get files");
 
@@ -581,22 +1605,23 @@ mod test {
             // delete_class
             {
                 let deleted = db
-                    .lock()
-                    .await
                     .delete_class(&classes[0].id)
                     .await
                     .expect("failed to delete file");
 
+                // ファイルを変えたので updatedAt は進んでいる
+                assert!(deleted.updated_at >= classes[0].updated_at);
+                classes[0].updated_at = deleted.updated_at;
                 assert_eq!(classes[0], deleted);
                 classes.remove(0);
 
-                let res_classes = db.lock().await.get_class_by_id(&deleted.id).await;
+                let res_classes = db.get_class_by_id(&deleted.id).await;
                 assert_eq!(res_classes, Err(DatabaseError::ClassNotFound));
 
-                let res_classes = db
-                    .lock()
-                    .await
-                    .get_class_by_id(&classes[0].id)
+                let res = db.delete_class(&deleted.id).await;
+                assert_eq!(res, Err(DatabaseError::ClassNotFound));
+
+                db.get_class_by_id(&classes[0].id)
                     .await
                     .expect("expected to not deleted this one");
             }
@@ -607,6 +1632,6 @@ mod test {
             .threaded_scheduler()
             .build()
             .unwrap()
-            .block_on(test());
+            .block_on(test(url));
     }
 }
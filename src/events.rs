@@ -0,0 +1,395 @@
+use crate::model::{ClassID, File};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use chrono::Utc;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+// 再接続してきたクライアントに送り直すために、クラスごとに残しておくイベントの数
+const HISTORY_LEN: usize = 64;
+
+// 履歴を残しておくクラスの数。超えたら一番長くイベントが無いクラスの履歴から捨てる
+const MAX_CLASS_LOGS: usize = 1024;
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "type")]
+pub enum EventKind {
+    #[serde(rename = "fileAdded")]
+    FileAdded { file: File },
+
+    #[serde(rename = "fileDeleted")]
+    FileDeleted { file: File },
+
+    #[serde(rename = "fileUpdated")]
+    FileUpdated { file: File },
+
+    #[serde(rename = "classRenamed")]
+    ClassRenamed { name: String },
+
+    #[serde(rename = "classDeleted")]
+    ClassDeleted,
+
+    // 取りこぼしたイベントを送り直せないので、クライアントに全部取り直してもらう
+    #[serde(rename = "resync")]
+    Resync,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::FileAdded { .. } => "fileAdded",
+            EventKind::FileDeleted { .. } => "fileDeleted",
+            EventKind::FileUpdated { .. } => "fileUpdated",
+            EventKind::ClassRenamed { .. } => "classRenamed",
+            EventKind::ClassDeleted => "classDeleted",
+            EventKind::Resync => "resync",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, JsonSchema)]
+pub struct ClassEvent {
+    #[serde(rename = "classID")]
+    pub class_id: ClassID,
+
+    // クラスごとの連番 (SSEのイベントIDに使う)
+    // 起動した時刻 (ミリ秒) の 1000 倍から始まるので、再起動しても前の連番より大きくなる
+    pub seq: u64,
+
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+struct ClassLog {
+    seq: u64,
+    history: VecDeque<ClassEvent>,
+
+    // 捨てる履歴を選ぶための、最後に publish された順番
+    published: u64,
+}
+
+// クラスの変更をWebSocketやSSEで待っている人に配る
+// 全クラス分を1本のチャンネルに流すので、受け取る側でクラスIDを見て絞り込む
+pub struct EventHub {
+    state: Mutex<HubState>,
+}
+
+struct HubState {
+    // close() したら None になる
+    sender: Option<broadcast::Sender<ClassEvent>>,
+    logs: HashMap<ClassID, ClassLog>,
+
+    // 履歴の無いクラスの連番はここから始める
+    // これまでに配ったどの連番よりも小さくならないので、履歴を捨てたクラスに
+    // 古い Last-Event-ID で繋いできても、送り直せないことが分かる
+    seq_base: u64,
+
+    // publish した回数
+    published: u64,
+}
+
+impl HubState {
+    fn subscribe(&self) -> broadcast::Receiver<ClassEvent> {
+        match &self.sender {
+            Some(sender) => sender.subscribe(),
+
+            // 閉じた後に来た購読は、すぐに終わる受信側を返す
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    fn current_seq(&self, class_id: &ClassID) -> u64 {
+        self.logs.get(class_id).map_or(self.seq_base, |log| log.seq)
+    }
+
+    fn log_mut(&mut self, class_id: &ClassID) -> &mut ClassLog {
+        if !self.logs.contains_key(class_id) && self.logs.len() >= MAX_CLASS_LOGS {
+            let oldest = self
+                .logs
+                .iter()
+                .min_by_key(|(_, log)| log.published)
+                .map(|(id, _)| id.clone());
+
+            if let Some(oldest) = oldest {
+                self.logs.remove(&oldest);
+            }
+        }
+
+        let seq_base = self.seq_base;
+
+        self.logs
+            .entry(class_id.clone())
+            .or_insert_with(|| ClassLog {
+                seq: seq_base,
+                history: VecDeque::new(),
+                published: 0,
+            })
+    }
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        // 連番は覚えておかないので、再起動の前より大きくなるように起動した時刻から始める
+        Self::with_seq_base(Utc::now().timestamp_millis() as u64 * 1000)
+    }
+
+    fn with_seq_base(seq_base: u64) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        Self {
+            state: Mutex::new(HubState {
+                sender: Some(sender),
+                logs: HashMap::new(),
+                seq_base,
+                published: 0,
+            }),
+        }
+    }
+
+    pub fn publish(&self, class_id: &ClassID, kind: EventKind) {
+        // 連番の採番と送信を同じロックの中でやって、受け取る側で順番が前後しないようにする
+        let mut state = self.state.lock().unwrap();
+
+        state.published += 1;
+        let published = state.published;
+
+        let log = state.log_mut(class_id);
+
+        log.seq += 1;
+        log.published = published;
+
+        let event = ClassEvent {
+            class_id: class_id.clone(),
+            seq: log.seq,
+            kind,
+        };
+
+        if let EventKind::ClassDeleted = event.kind {
+            state.logs.remove(class_id);
+        } else {
+            log.history.push_back(event.clone());
+
+            if log.history.len() > HISTORY_LEN {
+                log.history.pop_front();
+            }
+        }
+
+        state.seq_base = state.seq_base.max(event.seq);
+
+        // 誰も購読していないときはErrが返るが、捨てて問題ない
+        if let Some(sender) = &state.sender {
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ClassEvent> {
+        self.state.lock().unwrap().subscribe()
+    }
+
+    // 指定したクラスの次のイベントを待つ。閉じたら None
+    // 受信が追いつかなかったときは、どれを取りこぼしたか分からないので Resync を返す
+    pub async fn next_event(
+        &self,
+        receiver: &mut broadcast::Receiver<ClassEvent>,
+        class_id: &ClassID,
+    ) -> Option<ClassEvent> {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if &event.class_id == class_id => return Some(event),
+                Ok(_) => continue,
+
+                Err(broadcast::RecvError::Lagged(count)) => {
+                    log::warn!("class {:?} subscriber lagged {} events", class_id, count);
+                    return Some(self.resync(class_id));
+                }
+
+                Err(broadcast::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    // 今の連番で Resync を作る
+    fn resync(&self, class_id: &ClassID) -> ClassEvent {
+        let state = self.state.lock().unwrap();
+
+        ClassEvent {
+            class_id: class_id.clone(),
+            seq: state.current_seq(class_id),
+            kind: EventKind::Resync,
+        }
+    }
+
+    // last_seq より後のイベントを返しつつ購読を始める
+    // 履歴が足りず送り直せないときは、代わりに今の連番を Err で返す
+    pub fn subscribe_from(
+        &self,
+        class_id: &ClassID,
+        last_seq: u64,
+    ) -> (
+        Result<Vec<ClassEvent>, u64>,
+        broadcast::Receiver<ClassEvent>,
+    ) {
+        let state = self.state.lock().unwrap();
+        let receiver = state.subscribe();
+
+        // 履歴が無いのは、起動してから (または履歴を捨ててから) イベントが無いとき
+        // その間に他のクラスのイベントで seq_base が進んでいなければ、取りこぼしは無い
+        let log = match state.logs.get(class_id) {
+            Some(log) => log,
+            None if last_seq == state.seq_base => return (Ok(vec![]), receiver),
+            None => return (Err(state.seq_base), receiver),
+        };
+
+        let oldest_seq = log.history.front().map_or(log.seq + 1, |e| e.seq);
+
+        // サーバが再起動して連番が巻き戻った場合もここで弾かれる
+        if last_seq > log.seq || last_seq + 1 < oldest_seq {
+            return (Err(log.seq), receiver);
+        }
+
+        let missed = log
+            .history
+            .iter()
+            .filter(|e| e.seq > last_seq)
+            .cloned()
+            .collect();
+
+        (Ok(missed), receiver)
+    }
+
+    // 送信側を捨てて、購読中のストリームを全部終わらせる
+    // サーバを止めるときに、繋ぎっぱなしの接続を待たずに済むようにする
+    pub fn close(&self) {
+        self.state.lock().unwrap().sender = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn subscribe_from_replays_missed_events() {
+        let hub = EventHub::with_seq_base(0);
+        let class_id = ClassID(Uuid::new_v4());
+
+        for name in &["a", "b", "c"] {
+            let name = name.to_string();
+            hub.publish(&class_id, EventKind::ClassRenamed { name });
+        }
+
+        let (missed, _) = hub.subscribe_from(&class_id, 1);
+        let seqs = missed.unwrap().iter().map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![2, 3]);
+
+        let (missed, _) = hub.subscribe_from(&class_id, 3);
+        assert!(missed.unwrap().is_empty());
+
+        // 再起動などで連番が合わない
+        let (missed, _) = hub.subscribe_from(&class_id, 10);
+        assert_eq!(missed.unwrap_err(), 3);
+    }
+
+    #[test]
+    fn subscribe_from_detects_truncated_history() {
+        let hub = EventHub::with_seq_base(0);
+        let class_id = ClassID(Uuid::new_v4());
+
+        for _ in 0..HISTORY_LEN + 2 {
+            hub.publish(&class_id, EventKind::ClassRenamed { name: "a".into() });
+        }
+
+        let (missed, _) = hub.subscribe_from(&class_id, 1);
+        assert_eq!(missed.unwrap_err(), HISTORY_LEN as u64 + 2);
+
+        let (missed, _) = hub.subscribe_from(&class_id, 2);
+        assert_eq!(missed.unwrap().len(), HISTORY_LEN);
+    }
+
+    #[tokio::test]
+    async fn next_event_resyncs_after_lagging() {
+        let hub = EventHub::new();
+        let class_id = ClassID(Uuid::new_v4());
+        let mut receiver = hub.subscribe();
+
+        // 他のクラスのイベントは飛ばす
+        hub.publish(&ClassID(Uuid::new_v4()), EventKind::ClassDeleted);
+        hub.publish(&class_id, EventKind::ClassRenamed { name: "a".into() });
+
+        let first = hub.next_event(&mut receiver, &class_id).await.unwrap();
+        assert_eq!(first.kind.name(), "classRenamed");
+
+        for _ in 0..CHANNEL_CAPACITY + 1 {
+            hub.publish(&class_id, EventKind::ClassRenamed { name: "b".into() });
+        }
+
+        let event = hub.next_event(&mut receiver, &class_id).await.unwrap();
+        assert_eq!(event.kind.name(), "resync");
+        assert_eq!(event.seq, first.seq + CHANNEL_CAPACITY as u64 + 1);
+    }
+
+    #[test]
+    fn subscribe_from_resyncs_after_restart() {
+        let hub = EventHub::with_seq_base(1000);
+        let class_id = ClassID(Uuid::new_v4());
+
+        for _ in 0..3 {
+            hub.publish(&class_id, EventKind::ClassRenamed { name: "a".into() });
+        }
+
+        // 再起動すると履歴は無くなるが、連番は前より大きいところから始まる
+        let hub = EventHub::with_seq_base(2000);
+
+        let (missed, _) = hub.subscribe_from(&class_id, 1003);
+        assert_eq!(missed.unwrap_err(), 2000);
+
+        hub.publish(&class_id, EventKind::ClassRenamed { name: "b".into() });
+
+        let (missed, _) = hub.subscribe_from(&class_id, 1003);
+        assert_eq!(missed.unwrap_err(), 2001);
+
+        // Resync で受け取った連番からなら送り直せる
+        let (missed, _) = hub.subscribe_from(&class_id, 2000);
+        let seqs = missed.unwrap().iter().map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs, vec![2001]);
+    }
+
+    #[test]
+    fn class_logs_are_bounded() {
+        let hub = EventHub::with_seq_base(0);
+        let first = ClassID(Uuid::new_v4());
+
+        hub.publish(&first, EventKind::ClassRenamed { name: "a".into() });
+
+        for _ in 0..MAX_CLASS_LOGS {
+            let class_id = ClassID(Uuid::new_v4());
+            hub.publish(&class_id, EventKind::ClassRenamed { name: "a".into() });
+        }
+
+        assert_eq!(hub.state.lock().unwrap().logs.len(), MAX_CLASS_LOGS);
+
+        // 履歴を捨てたクラスには送り直せない
+        let (missed, _) = hub.subscribe_from(&first, 1);
+        assert_eq!(missed.unwrap_err(), MAX_CLASS_LOGS as u64 + 1);
+
+        // 連番は捨てる前より大きいところから続く
+        let mut receiver = hub.subscribe();
+        hub.publish(&first, EventKind::ClassRenamed { name: "b".into() });
+        assert_eq!(receiver.try_recv().unwrap().seq, MAX_CLASS_LOGS as u64 + 2);
+    }
+
+    #[tokio::test]
+    async fn close_ends_subscriptions() {
+        let hub = EventHub::new();
+        let mut receiver = hub.subscribe();
+
+        hub.close();
+
+        assert!(receiver.recv().await.is_err());
+        assert!(hub.subscribe().recv().await.is_err());
+    }
+}
@@ -1,32 +1,50 @@
 mod api;
+mod auth;
 mod db;
+mod events;
 mod model;
+mod storage;
 
+use crate::auth::Auth;
+use crate::db::file::FileDB;
 use crate::db::mem::MemoryDB;
 use crate::db::mongo::MongoDB;
+use crate::db::{Database, MarkerPolicy};
+use crate::events::EventHub;
+use crate::storage::local::LocalStorage;
+use rand::rngs::OsRng;
+use rand::RngCore;
 use std::env;
+use std::num::NonZeroU64;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-
-type Synced<D> = Arc<Mutex<D>>;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
 
 #[tokio::main]
 async fn main() {
     setup_logger();
 
+    // サーバは起動せずに、DBのインデックスとドキュメントの形に問題がないかだけを調べる
+    if env::args().skip(1).any(|arg| arg == "--check-schema") {
+        check_schema().await;
+        return;
+    }
+
     let port = get_port();
 
     match env::var("DATABASE").ok() {
         Some(db_name) => match db_name.as_str() {
             "memory" => use_memory_db(port).await,
             "mongo" => use_mongo_db(port).await,
+            "file" => use_file_db(port).await,
 
-            _ => panic!("Set DATABASE env var to \"memory\" or \"mongo\""),
+            _ => panic!("Set DATABASE env var to \"memory\", \"mongo\" or \"file\""),
         },
 
         None => {
             log::warn!(
-                "DATABASE env var not set. fallbacking to memory DB, data lost occurs on restart!"
+                "DATABASE env var not set. fallbacking to memory DB, data lost occurs on restart unless MEMORY_SNAPSHOT_PATH is set!"
             );
 
             use_memory_db(port).await
@@ -35,22 +53,151 @@ async fn main() {
 }
 
 async fn use_memory_db(port: u16) {
-    let db = Arc::new(Mutex::new(MemoryDB::new()));
-    api::serve(port, db).await;
+    let snapshot_path = env::var("MEMORY_SNAPSHOT_PATH").ok();
+
+    let db = match &snapshot_path {
+        Some(path) => MemoryDB::with_snapshot(path)
+            .await
+            .expect("Failed to load MEMORY_SNAPSHOT_PATH snapshot"),
+
+        None => MemoryDB::new(),
+    };
+
+    let db = Arc::new(db);
+
+    let periodic = snapshot_path.as_ref().map(|_| {
+        let interval = Duration::from_secs(get_snapshot_interval());
+        let snapshot_db = Arc::clone(&db);
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(async move {
+            let stop = async {
+                stop_rx.await.ok();
+            };
+
+            snapshot_db.save_snapshot_periodically(interval, stop).await
+        });
+
+        (stop_tx, task)
+    });
+
+    run(port, Arc::clone(&db)).await;
+
+    // 定期的な書き出しを止めて、書き出し中のものが終わってから最後の書き出しをする
+    if let Some((stop_tx, task)) = periodic {
+        let _ = stop_tx.send(());
+        let _ = task.await;
+    }
+
+    close(db).await;
+}
+
+// 問題があれば全部表示して、終了コード 1 で終わる
+async fn check_schema() {
+    match env::var("DATABASE").as_ref().map(|e| e.as_str()) {
+        Ok("mongo") => {}
+        _ => panic!("--check-schema is only supported with DATABASE=\"mongo\""),
+    }
+
+    let url = env::var("MONGO_URL").expect("Set MONGO_URL to MongoDB URL");
+    let problems = MongoDB::check_schema(&url)
+        .await
+        .expect("Failed to connect MongoDB");
+
+    if problems.is_empty() {
+        println!("schema ok");
+        return;
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    std::process::exit(1);
 }
 
 async fn use_mongo_db(port: u16) {
     let url = env::var("MONGO_URL").expect("Set MONGO_URL to MongoDB URL");
     let db = MongoDB::new(&url).await.expect("Failed to connect MongoDB");
-    let db = Arc::new(Mutex::new(db));
+    let db = Arc::new(db);
+
+    run(port, Arc::clone(&db)).await;
+    close(db).await;
+}
+
+async fn use_file_db(port: u16) {
+    let path = env::var("FILE_DB_PATH").unwrap_or_else(|_| "data".into());
+    let db = FileDB::open(&path)
+        .await
+        .expect("Failed to open FILE_DB_PATH database");
+    let db = Arc::new(db);
+
+    run(port, Arc::clone(&db)).await;
+    close(db).await;
+}
+
+// 終了のシグナルが来たら、処理中のリクエストを待ってから戻る
+async fn run(port: u16, db: Arc<impl Database>) {
+    api::serve(
+        port,
+        Arc::clone(&db),
+        get_storage(),
+        get_auth(),
+        Arc::new(EventHub::new()),
+        get_marker_policy(),
+        shutdown_signal(),
+        Duration::from_secs(get_shutdown_timeout()),
+    )
+    .await;
+}
 
-    api::serve(port, db).await;
+// 他にDBを使うものが残っていない状態で呼ぶ
+async fn close(db: Arc<impl Database>) {
+    if let Err(e) = db.shutdown().await {
+        log::error!("failed to shut down database: {}", e);
+    }
+
+    log::info!("server stopped");
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen SIGTERM");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("received SIGINT, shutting down"),
+        _ = terminate.recv() => log::info!("received SIGTERM, shutting down"),
+    }
+}
+
+fn get_storage() -> Arc<LocalStorage> {
+    let path = env::var("STORAGE_PATH").unwrap_or_else(|_| "storage".into());
+    let storage = LocalStorage::new(&path).expect("Failed to prepare STORAGE_PATH directory");
+
+    Arc::new(storage)
+}
+
+fn get_auth() -> Arc<Auth> {
+    let secret = match env::var("TOKEN_SECRET").ok() {
+        Some(secret) => secret.into_bytes(),
+
+        None => {
+            log::warn!(
+                "TOKEN_SECRET env var not set. using random secret, issued tokens will be invalidated on restart!"
+            );
+
+            let mut secret = vec![0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            secret
+        }
+    };
+
+    Arc::new(Auth::new(&secret))
 }
 
 fn setup_logger() {
     let dotenv_result = dotenv::dotenv();
 
-    if let None = env::var_os("RUST_LOG") {
+    if env::var_os("RUST_LOG").is_none() {
         env::set_var("RUST_LOG", "INFO");
     }
 
@@ -65,6 +212,35 @@ fn setup_logger() {
     }
 }
 
+fn get_marker_policy() -> MarkerPolicy {
+    match env::var("MARKER_POLICY").as_ref().map(|e| e.as_str()) {
+        Ok("reject") | Err(_) => MarkerPolicy::Reject,
+        Ok("replace") => MarkerPolicy::Replace,
+
+        _ => panic!("Set MARKER_POLICY env var to \"reject\" or \"replace\""),
+    }
+}
+
+fn get_shutdown_timeout() -> u64 {
+    env::var("SHUTDOWN_TIMEOUT")
+        .as_ref()
+        .map(|e| e.as_str())
+        .unwrap_or("5")
+        .parse()
+        .expect("\"SHUTDOWN_TIMEOUT\" should be seconds to wait for requests on shutdown.")
+}
+
+fn get_snapshot_interval() -> u64 {
+    env::var("MEMORY_SNAPSHOT_INTERVAL")
+        .as_ref()
+        .map(|e| e.as_str())
+        .unwrap_or("60")
+        // 0 秒ごとには書き出せないので、起動時に断る
+        .parse::<NonZeroU64>()
+        .expect("\"MEMORY_SNAPSHOT_INTERVAL\" should be seconds (1 or more) between snapshots.")
+        .get()
+}
+
 fn get_port() -> u16 {
     env::var("PORT")
         .as_ref()
@@ -1,19 +1,24 @@
 use crate::db::{Database, DatabaseError};
-use crate::Synced;
+use chrono::{DateTime, TimeZone, Utc};
 use rand::rngs::OsRng;
 use rand::seq::SliceRandom;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, JsonSchema)]
 pub struct ClassID(pub Uuid);
 
 impl ClassID {
-    pub async fn new(db: &Synced<impl Database>) -> Result<Self, DatabaseError> {
+    pub async fn new(db: &impl Database) -> Result<Self, DatabaseError> {
         loop {
             let generated_id = Self(Uuid::new_v4());
-            if !db.lock().await.class_id_exists(&generated_id).await? {
+            if !db.class_id_exists(&generated_id).await? {
                 break Ok(generated_id);
             }
         }
@@ -28,14 +33,14 @@ impl FromStr for ClassID {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct FileID(pub Uuid);
 
 impl FileID {
-    pub async fn new(db: &Synced<impl Database>) -> Result<Self, DatabaseError> {
+    pub async fn new(db: &impl Database) -> Result<Self, DatabaseError> {
         loop {
             let generated_id = Self(Uuid::new_v4());
-            if !db.lock().await.file_id_exists(&generated_id).await? {
+            if !db.file_id_exists(&generated_id).await? {
                 break Ok(generated_id);
             }
         }
@@ -50,11 +55,11 @@ impl FromStr for FileID {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct PassPhrase(pub String);
 
 impl PassPhrase {
-    pub async fn new(db: &Synced<impl Database>) -> Result<Self, DatabaseError> {
+    pub async fn new(db: &impl Database) -> Result<Self, DatabaseError> {
         const SEED_STR: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
         const PASS_LEN: usize = 6;
 
@@ -71,20 +76,89 @@ impl PassPhrase {
 
             let pass = Self(text);
 
-            if !db.lock().await.pass_phrase_exists(&pass).await? {
+            if !db.pass_phrase_exists(&pass).await? {
                 break Ok(pass);
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct TeacherID(pub Uuid);
+
+impl TeacherID {
+    pub async fn new(db: &impl Database) -> Result<Self, DatabaseError> {
+        loop {
+            let generated_id = Self(Uuid::new_v4());
+            if !db.teacher_id_exists(&generated_id).await? {
+                break Ok(generated_id);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct ArMarkerID(pub String);
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct EpochTime(pub i64);
+// 時刻
+// JSONでもDBでも UNIX時間のミリ秒 (整数) で読み書きする
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EpochTime(DateTime<Utc>);
+
+impl EpochTime {
+    // 読み書きで値が変わらないように、ミリ秒より細かいところは切り捨てる
+    pub fn now() -> Self {
+        EpochTime::from_millis(Utc::now().timestamp_millis()).unwrap()
+    }
+
+    // chrono で表せない範囲なら None
+    pub fn from_millis(millis: i64) -> Option<Self> {
+        Utc.timestamp_millis_opt(millis).single().map(EpochTime)
+    }
+
+    pub fn millis(&self) -> i64 {
+        self.0.timestamp_millis()
+    }
+}
+
+// 時刻を持つ前に作られたデータは 1970-01-01 になる
+impl Default for EpochTime {
+    fn default() -> Self {
+        EpochTime(Utc.timestamp(0, 0))
+    }
+}
+
+impl JsonSchema for EpochTime {
+    fn schema_name() -> String {
+        "EpochTime".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::Integer.into()),
+            format: Some("int64".into()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl Serialize for EpochTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.millis())
+    }
+}
+
+impl<'de> Deserialize<'de> for EpochTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let millis = i64::deserialize(deserializer)?;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+        EpochTime::from_millis(millis)
+            .ok_or_else(|| de::Error::custom(format!("timestamp {} is out of range", millis)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 pub struct Class {
     pub name: String,
     pub id: ClassID,
@@ -92,24 +166,42 @@ pub struct Class {
     #[serde(rename = "passPhrase")]
     pub pass_phrase: PassPhrase,
 
+    pub owner: TeacherID,
+
+    // 作られた時刻と最後に変更された時刻 (サーバ側で付ける)
+    #[serde(rename = "createdAt", default)]
+    pub created_at: EpochTime,
+
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: EpochTime,
+
     pub files: Vec<File>,
 }
 
 impl Class {
-    pub async fn new(db: &Synced<impl Database>, name: String) -> Result<Self, DatabaseError> {
+    pub async fn new(
+        db: &impl Database,
+        name: String,
+        owner: TeacherID,
+    ) -> Result<Self, DatabaseError> {
         let id = ClassID::new(db).await?;
         let pass_phrase = PassPhrase::new(db).await?;
 
+        let now = EpochTime::now();
+
         Ok(Class {
             id,
             pass_phrase,
+            owner,
             name: name.to_string(),
+            created_at: now,
+            updated_at: now,
             files: vec![],
         })
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct File {
     pub id: FileID,
 
@@ -122,29 +214,69 @@ pub struct File {
 
 impl File {
     pub async fn new(
-        db: &Synced<impl Database>,
+        db: &impl Database,
         marker_id: ArMarkerID,
         filename: String,
-        created_at: EpochTime,
+        client_created_at: Option<EpochTime>,
     ) -> Result<File, DatabaseError> {
         let id = FileID::new(db).await?;
+        let now = EpochTime::now();
 
         Ok(File {
             id,
             marker_id,
             resource_info: ResourceInfo {
                 filename,
-                created_at,
+                created_at: now,
+                updated_at: now,
+                client_created_at,
             },
         })
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct ResourceInfo {
     #[serde(rename = "fileName")]
     pub filename: String,
 
+    // サーバ側で付ける時刻
     #[serde(rename = "createdAt")]
     pub created_at: EpochTime,
+
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: EpochTime,
+
+    // クライアントが送ってきた作成時刻 (端末の時計なので当てにはしない)
+    #[serde(
+        rename = "clientCreatedAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub client_created_at: Option<EpochTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Teacher {
+    pub id: TeacherID,
+    pub name: String,
+
+    #[serde(rename = "passwordHash")]
+    pub password_hash: String,
+}
+
+impl Teacher {
+    pub async fn new(
+        db: &impl Database,
+        name: String,
+        password_hash: String,
+    ) -> Result<Self, DatabaseError> {
+        let id = TeacherID::new(db).await?;
+
+        Ok(Teacher {
+            id,
+            name,
+            password_hash,
+        })
+    }
 }
@@ -0,0 +1,27 @@
+pub mod local;
+
+use crate::model::FileID;
+use async_trait::async_trait;
+use thiserror::Error;
+
+// Databaseにはファイルのメタデータだけを持たせて、中身(バイナリ)はこっちに置く
+#[async_trait]
+pub trait Storage: Send + Sync + 'static {
+    // 同じIDの中身が既にあれば上書きせずに AlreadyExists を返す
+    async fn save(&self, file_id: &FileID, content: &[u8]) -> Result<(), StorageError>;
+    async fn load(&self, file_id: &FileID) -> Result<Vec<u8>, StorageError>;
+    async fn delete(&self, file_id: &FileID) -> Result<(), StorageError>;
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StorageError {
+    #[error("specified file content not found")]
+    NotFound,
+
+    #[error("file content of the same id already exists")]
+    AlreadyExists,
+
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+}
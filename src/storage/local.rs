@@ -0,0 +1,101 @@
+use crate::model::FileID;
+use crate::storage::{Storage, StorageError};
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use uuid::Uuid;
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, StorageError> {
+        std::fs::create_dir_all(root.as_ref())?;
+
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path_of(&self, file_id: &FileID) -> PathBuf {
+        self.root.join(file_id.0.to_string())
+    }
+}
+
+fn map_io_error(e: std::io::Error) -> StorageError {
+    match e.kind() {
+        ErrorKind::NotFound => StorageError::NotFound,
+        _ => StorageError::IOError(e),
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn save(&self, file_id: &FileID, content: &[u8]) -> Result<(), StorageError> {
+        // 書きかけのファイルを読まれないように一時ファイルに書いてから置く
+        // rename だと同じIDの中身を上書きしてしまうので、置き先が無いときだけ成功する hard_link を使う
+        // 一時ファイルは同じIDで同時に保存されても被らない名前にする
+        let path = self.path_of(file_id);
+        let temp_path = self
+            .root
+            .join(format!("{}.{}.tmp", file_id.0, Uuid::new_v4()));
+
+        let result = async {
+            fs::write(&temp_path, content).await?;
+            fs::hard_link(&temp_path, &path).await
+        }
+        .await;
+
+        if let Err(e) = fs::remove_file(&temp_path).await {
+            if e.kind() != ErrorKind::NotFound {
+                log::warn!("failed to remove {:?}: {}", temp_path, e);
+            }
+        }
+
+        result.map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => StorageError::AlreadyExists,
+            _ => StorageError::IOError(e),
+        })
+    }
+
+    async fn load(&self, file_id: &FileID) -> Result<Vec<u8>, StorageError> {
+        fs::read(self.path_of(file_id)).await.map_err(map_io_error)
+    }
+
+    async fn delete(&self, file_id: &FileID) -> Result<(), StorageError> {
+        fs::remove_file(self.path_of(file_id))
+            .await
+            .map_err(map_io_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn save_does_not_overwrite() {
+        let root = std::env::temp_dir().join(format!("blackboard-test-{}", Uuid::new_v4()));
+        let storage = LocalStorage::new(&root).unwrap();
+        let id = FileID(Uuid::new_v4());
+
+        storage.save(&id, b"first").await.unwrap();
+
+        assert!(matches!(
+            storage.save(&id, b"second").await,
+            Err(StorageError::AlreadyExists)
+        ));
+        assert_eq!(storage.load(&id).await.unwrap(), b"first");
+
+        // 一時ファイルは残らない
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+
+        storage.delete(&id).await.unwrap();
+        storage.save(&id, b"third").await.unwrap();
+        assert_eq!(storage.load(&id).await.unwrap(), b"third");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}